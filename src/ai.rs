@@ -0,0 +1,530 @@
+use crate::game::{self, Direction, Game, Player, PlayerIndex, DIRECTIONS};
+use crate::Point;
+use rand::rngs::SmallRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Decides where an AI player goes next. It's asked once per frame, after the frame has been
+/// run, for every AI player that is still alive. The game is only looked at, never changed; the
+/// returned direction is applied like a key press would be.
+pub trait AiStrategy: Send {
+    fn choose_direction(&mut self, game: &Game, player_i: PlayerIndex) -> Direction;
+
+    /// Something that went wrong since the last call, for the player to see
+    fn take_problem(&mut self) -> Option<String> {
+        None
+    }
+}
+
+/// The built-in strategies that an AI seat can be given
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum AiKind {
+    /// Keeps going until blocked, then takes the first free direction
+    #[default]
+    Greedy,
+    /// Mostly keeps going, but now and then turns at random, never into something
+    RandomSafe,
+    /// Heads for the largest open area
+    FloodFill,
+    /// Keeps close to walls and trails, leaving open space for later
+    WallHugger,
+    /// Claims the cells that it can reach before any opponent can
+    Voronoi,
+    /// Searches the moves of itself and the closest opponent a few frames ahead
+    Minimax(Difficulty),
+}
+
+/// How far ahead the minimax bot looks. It never takes longer than its time budget though, so
+/// on a big arena or with a fast tick, even `Hard` may not get very deep.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Difficulty {
+    Easy,
+    Medium,
+    Hard,
+}
+
+impl Difficulty {
+    // In frames, i.e. one move by each of the two players
+    fn max_depth(&self) -> u32 {
+        match self {
+            Difficulty::Easy => 1,
+            Difficulty::Medium => 3,
+            Difficulty::Hard => 64,
+        }
+    }
+}
+
+impl AiKind {
+    pub const ALL: [AiKind; 8] = [
+        AiKind::Greedy,
+        AiKind::RandomSafe,
+        AiKind::FloodFill,
+        AiKind::WallHugger,
+        AiKind::Voronoi,
+        AiKind::Minimax(Difficulty::Easy),
+        AiKind::Minimax(Difficulty::Medium),
+        AiKind::Minimax(Difficulty::Hard),
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AiKind::Greedy => "greedy",
+            AiKind::RandomSafe => "random",
+            AiKind::FloodFill => "flood-fill",
+            AiKind::WallHugger => "wall-hugger",
+            AiKind::Voronoi => "voronoi",
+            AiKind::Minimax(Difficulty::Easy) => "minimax:easy",
+            AiKind::Minimax(Difficulty::Medium) => "minimax:medium",
+            AiKind::Minimax(Difficulty::Hard) => "minimax:hard",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        if name == "minimax" {
+            return Some(AiKind::Minimax(Difficulty::Hard));
+        }
+        Self::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+
+    /// `time_budget` is how long the strategy may think each frame. Only the search based ones
+    /// come anywhere close to it.
+    pub fn create(&self, time_budget: Duration) -> Box<dyn AiStrategy> {
+        self.create_seeded(time_budget, rand::random())
+    }
+
+    /// Like `create`, but the strategies that make random choices make the same ones every time
+    /// for the same seed
+    pub fn create_seeded(&self, time_budget: Duration, seed: u64) -> Box<dyn AiStrategy> {
+        match self {
+            AiKind::Greedy => Box::new(Greedy),
+            AiKind::RandomSafe => Box::new(RandomSafe(SmallRng::seed_from_u64(seed))),
+            AiKind::FloodFill => Box::new(FloodFill),
+            AiKind::WallHugger => Box::new(WallHugger),
+            AiKind::Voronoi => Box::new(Voronoi),
+            AiKind::Minimax(difficulty) => Box::new(Minimax {
+                difficulty: *difficulty,
+                time_budget,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for AiKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+struct Greedy;
+
+impl AiStrategy for Greedy {
+    fn choose_direction(&mut self, game: &Game, player_i: PlayerIndex) -> Direction {
+        let player = &game.players[player_i];
+        let head = player.head();
+        if game.is_vacant(game::translated(head, player.direction)) {
+            return player.direction;
+        }
+        DIRECTIONS
+            .into_iter()
+            .find(|dir| game.is_vacant(game::translated(head, *dir)))
+            .unwrap_or(player.direction)
+    }
+}
+
+struct RandomSafe(SmallRng);
+
+impl AiStrategy for RandomSafe {
+    fn choose_direction(&mut self, game: &Game, player_i: PlayerIndex) -> Direction {
+        let grid = Grid::new(game);
+        let player = &game.players[player_i];
+        let moves = grid.safe_moves(player.head());
+        let keep_going = moves.contains(&player.direction) && self.0.gen_bool(0.8);
+        if keep_going {
+            return player.direction;
+        }
+        moves
+            .choose(&mut self.0)
+            .copied()
+            .unwrap_or(player.direction)
+    }
+}
+
+struct FloodFill;
+
+impl AiStrategy for FloodFill {
+    fn choose_direction(&mut self, game: &Game, player_i: PlayerIndex) -> Direction {
+        let grid = Grid::new(game);
+        let player = &game.players[player_i];
+        best_move(player.direction, &grid.safe_moves(player.head()), |dir| {
+            grid.reachable_area(game::translated(player.head(), dir))
+        })
+    }
+}
+
+struct WallHugger;
+
+impl AiStrategy for WallHugger {
+    fn choose_direction(&mut self, game: &Game, player_i: PlayerIndex) -> Direction {
+        let grid = Grid::new(game);
+        let player = &game.players[player_i];
+        let head = player.head();
+        let moves = grid.safe_moves(head);
+
+        // Hugging a wall into a dead end is no good, so only moves that keep most of the open
+        // space within reach are considered
+        let areas: Vec<usize> = moves
+            .iter()
+            .map(|dir| grid.reachable_area(game::translated(head, *dir)))
+            .collect();
+        let largest_area = areas.iter().copied().max().unwrap_or(0);
+        let roomy_moves: Vec<Direction> = moves
+            .iter()
+            .zip(&areas)
+            .filter(|(_, area)| **area * 2 >= largest_area)
+            .map(|(dir, _)| *dir)
+            .collect();
+
+        best_move(player.direction, &roomy_moves, |dir| {
+            let next = game::translated(head, dir);
+            DIRECTIONS
+                .iter()
+                .filter(|d| !grid.is_free(game::translated(next, **d)))
+                .count()
+        })
+    }
+}
+
+struct Voronoi;
+
+impl AiStrategy for Voronoi {
+    fn choose_direction(&mut self, game: &Game, player_i: PlayerIndex) -> Direction {
+        let grid = Grid::new(game);
+        let player = &game.players[player_i];
+        let head = player.head();
+
+        let opponent_starts: Vec<Point> = game
+            .players
+            .iter()
+            .enumerate()
+            .filter(|(i, p)| *i != player_i && !p.crashed)
+            .flat_map(|(_, p)| {
+                let head = p.head();
+                DIRECTIONS.map(|dir| game::translated(head, dir))
+            })
+            .filter(|point| grid.is_free(*point))
+            .collect();
+        let opponent_distances = grid.distances(&opponent_starts);
+
+        best_move(player.direction, &grid.safe_moves(head), |dir| {
+            let own_distances = grid.distances(&[game::translated(head, dir)]);
+            let owned_cells = own_distances
+                .iter()
+                .zip(&opponent_distances)
+                .filter(|(own, opponent)| own < opponent)
+                .count();
+            // The reachable area breaks ties, e.g. once the players are walled off from each other
+            (
+                owned_cells,
+                grid.reachable_area(game::translated(head, dir)),
+            )
+        })
+    }
+}
+
+struct Minimax {
+    difficulty: Difficulty,
+    time_budget: Duration,
+}
+
+impl AiStrategy for Minimax {
+    fn choose_direction(&mut self, game: &Game, player_i: PlayerIndex) -> Direction {
+        // Everything counts against the budget, even taking the snapshot of a big arena
+        let deadline = Instant::now() + self.time_budget;
+        let grid = Grid::new(game);
+        // Good enough to fall back on if there isn't time for even the shallowest search
+        let mut best = roomiest_move(&grid, &game.players[player_i], deadline);
+
+        let head = game.players[player_i].head();
+        let distance = |p: Point| (p.0 - head.0).abs() + (p.1 - head.1).abs();
+        let opponent = game
+            .players
+            .iter()
+            .enumerate()
+            .filter(|(i, p)| *i != player_i && !p.crashed)
+            .map(|(_, p)| p.head())
+            .min_by_key(|p| distance(*p));
+        let opponent = match opponent {
+            Some(opponent) => opponent,
+            None => return best,
+        };
+
+        // Iterative deepening: each finished depth gives a better answer than the one before,
+        // and the search can be cut off at any time
+        let mut search = Search {
+            grid,
+            deadline,
+            timed_out: false,
+        };
+        for depth in 1..=self.difficulty.max_depth() {
+            match search.best_move(head, opponent, depth, best) {
+                Some((direction, score)) => {
+                    best = direction;
+                    if score.abs() >= WIN - MAX_PLY {
+                        // The outcome is decided, no matter how deep we look
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        best
+    }
+}
+
+const WIN: i32 = 1_000_000;
+const MAX_PLY: i32 = 1000;
+
+// Alpha-beta over simultaneous moves: our move is picked first, then the opponent's with our
+// move known (which is pessimistic, but safe), and then both are applied at once. All other
+// players are treated as standing still.
+struct Search {
+    grid: Grid,
+    deadline: Instant,
+    timed_out: bool,
+}
+
+impl Search {
+    fn best_move(
+        &mut self,
+        own_head: Point,
+        opponent_head: Point,
+        depth: u32,
+        first_guess: Direction,
+    ) -> Option<(Direction, i32)> {
+        // Trying the previous depth's answer first gives the most cut-offs
+        let mut moves = vec![first_guess];
+        moves.extend(DIRECTIONS.into_iter().filter(|dir| *dir != first_guess));
+
+        let mut best: Option<(Direction, i32)> = None;
+        let mut alpha = -WIN - 1;
+        for dir in moves {
+            let own_next = game::translated(own_head, dir);
+            let score = self.opponent_move(own_next, opponent_head, depth, 0, alpha, WIN + 1)?;
+            if best.is_none() || score > alpha {
+                alpha = score;
+                best = Some((dir, score));
+            }
+        }
+        best
+    }
+
+    fn own_move(
+        &mut self,
+        own_head: Point,
+        opponent_head: Point,
+        depth: u32,
+        ply: i32,
+        mut alpha: i32,
+        beta: i32,
+    ) -> Option<i32> {
+        let mut value = -WIN - 1;
+        for dir in DIRECTIONS {
+            let own_next = game::translated(own_head, dir);
+            let score = self.opponent_move(own_next, opponent_head, depth, ply, alpha, beta)?;
+            value = value.max(score);
+            alpha = alpha.max(value);
+            if alpha >= beta {
+                break;
+            }
+        }
+        Some(value)
+    }
+
+    fn opponent_move(
+        &mut self,
+        own_next: Point,
+        opponent_head: Point,
+        depth: u32,
+        ply: i32,
+        alpha: i32,
+        mut beta: i32,
+    ) -> Option<i32> {
+        let mut value = WIN + 1;
+        for opponent_dir in DIRECTIONS {
+            let opponent_next = game::translated(opponent_head, opponent_dir);
+            let score = self.run_frame(own_next, opponent_next, depth, ply + 1, alpha, beta)?;
+            value = value.min(score);
+            beta = beta.min(value);
+            if alpha >= beta {
+                break;
+            }
+        }
+        Some(value)
+    }
+
+    fn run_frame(
+        &mut self,
+        own_next: Point,
+        opponent_next: Point,
+        depth: u32,
+        ply: i32,
+        alpha: i32,
+        beta: i32,
+    ) -> Option<i32> {
+        // The clock is read at every node rather than every so many: a leaf searches the whole
+        // arena twice, which takes a while on a big one, and in a tight spot a deep search can go
+        // a long way on crashes alone without ever reaching a leaf
+        if self.timed_out || Instant::now() >= self.deadline {
+            self.timed_out = true;
+            return None;
+        }
+
+        let head_on = own_next == opponent_next;
+        let own_crash = head_on || !self.grid.is_free(own_next);
+        let opponent_crash = head_on || !self.grid.is_free(opponent_next);
+        // Crashing later is better than crashing now, and winning now is better than later
+        match (own_crash, opponent_crash) {
+            (true, true) => return Some(0),
+            (true, false) => return Some(-WIN + ply),
+            (false, true) => return Some(WIN - ply),
+            (false, false) => {}
+        }
+
+        let own_i = self.grid.index(own_next).unwrap();
+        let opponent_i = self.grid.index(opponent_next).unwrap();
+        self.grid.taken[own_i] = true;
+        self.grid.taken[opponent_i] = true;
+        let score = if depth <= 1 {
+            Some(self.evaluate(own_next, opponent_next))
+        } else {
+            self.own_move(own_next, opponent_next, depth - 1, ply, alpha, beta)
+        };
+        self.grid.taken[own_i] = false;
+        self.grid.taken[opponent_i] = false;
+        score
+    }
+
+    // The difference in how many cells each player can reach first
+    fn evaluate(&self, own_head: Point, opponent_head: Point) -> i32 {
+        let neighbours = |head: Point| DIRECTIONS.map(|dir| game::translated(head, dir));
+        let own = self.grid.distances(&neighbours(own_head));
+        let opponent = self.grid.distances(&neighbours(opponent_head));
+        own.iter()
+            .zip(&opponent)
+            .map(|(own, opponent)| match own.cmp(opponent) {
+                std::cmp::Ordering::Less => 1,
+                std::cmp::Ordering::Greater => -1,
+                std::cmp::Ordering::Equal => 0,
+            })
+            .sum()
+    }
+}
+
+// FloodFill's move, as far as there's time to measure the room that each move leaves, and else
+// just a safe one
+fn roomiest_move(grid: &Grid, player: &Player, deadline: Instant) -> Direction {
+    let head = player.head();
+    let moves = grid.safe_moves(head);
+    let mut areas = Vec::with_capacity(moves.len());
+    for dir in &moves {
+        if Instant::now() >= deadline {
+            return best_move(player.direction, &moves, |_| ());
+        }
+        areas.push(grid.reachable_area(game::translated(head, *dir)));
+    }
+    best_move(player.direction, &moves, |dir| {
+        areas[moves.iter().position(|m| *m == dir).unwrap()]
+    })
+}
+
+// The move with the highest score, preferring to keep going straight on a tie
+fn best_move<S: Ord>(
+    current: Direction,
+    moves: &[Direction],
+    mut score: impl FnMut(Direction) -> S,
+) -> Direction {
+    moves
+        .iter()
+        .map(|dir| ((score(*dir), *dir == current), *dir))
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, dir)| dir)
+        .unwrap_or(current)
+}
+
+// A snapshot of which cells are taken, so that searching the arena doesn't have to go through
+// every player's line for each cell
+struct Grid {
+    width: i32,
+    height: i32,
+    taken: Vec<bool>,
+}
+
+impl Grid {
+    fn new(game: &Game) -> Self {
+        let (width, height) = (game.size().0 as i32, game.size().1 as i32);
+        let mut grid = Self {
+            width,
+            height,
+            taken: vec![false; (width * height) as usize],
+        };
+        for player in &game.players {
+            for point in &player.line {
+                if let Some(i) = grid.index(*point) {
+                    grid.taken[i] = true;
+                }
+            }
+        }
+        grid
+    }
+
+    fn index(&self, point: Point) -> Option<usize> {
+        let inside = point.0 >= 0 && point.1 >= 0 && point.0 < self.width && point.1 < self.height;
+        inside.then(|| (point.1 * self.width + point.0) as usize)
+    }
+
+    fn is_free(&self, point: Point) -> bool {
+        self.index(point).is_some_and(|i| !self.taken[i])
+    }
+
+    fn safe_moves(&self, head: Point) -> Vec<Direction> {
+        DIRECTIONS
+            .into_iter()
+            .filter(|dir| self.is_free(game::translated(head, *dir)))
+            .collect()
+    }
+
+    fn reachable_area(&self, from: Point) -> usize {
+        self.distances(&[from])
+            .iter()
+            .filter(|d| **d != u32::MAX)
+            .count()
+    }
+
+    // Steps from the closest of the starting points to every cell, or u32::MAX if out of reach
+    fn distances(&self, starts: &[Point]) -> Vec<u32> {
+        let mut distances = vec![u32::MAX; self.taken.len()];
+        let mut queue = VecDeque::new();
+        for start in starts {
+            if let Some(i) = self.index(*start).filter(|i| !self.taken[*i]) {
+                distances[i] = 0;
+                queue.push_back(*start);
+            }
+        }
+        while let Some(point) = queue.pop_front() {
+            let distance = distances[self.index(point).unwrap()];
+            for dir in DIRECTIONS {
+                let next = game::translated(point, dir);
+                if let Some(i) = self.index(next) {
+                    if !self.taken[i] && distances[i] == u32::MAX {
+                        distances[i] = distance + 1;
+                        queue.push_back(next);
+                    }
+                }
+            }
+        }
+        distances
+    }
+}
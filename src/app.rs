@@ -1,117 +1,126 @@
+use crate::ai::AiStrategy;
+use crate::bot::ExternalBot;
+use crate::config::{self, KeyBinding, Seat, Settings, MAX_SEATS};
+use crate::events::EventLog;
 use crate::game::{
-    self, Direction, FrameEvent, Game, Player, PlayerIndex, DIRECTIONS, DOWN, LEFT, RIGHT, UP,
+    self, Direction, Game, Player, PlayerColor, PlayerIndex, StartPosition, DOWN, LEFT, RIGHT, UP,
 };
-use crate::net::{NetResult, NetworkEvent, Networking, Outcome};
+use crate::net::{NetResult, NetworkEvent, Networking, Outcome, Rules, MAX_CHAT_MESSAGE_LEN};
+use crate::stats::StatsRecorder;
+use crate::transport::Transport;
 use crate::user_interface::TerminalUi;
-use crate::Point;
+use anyhow::Context;
 use crossterm::event::Event::Key;
-use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
-use std::collections::HashMap;
-use std::net::TcpStream;
-use std::sync::mpsc;
-use std::sync::mpsc::Sender;
-use std::thread;
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use futures::StreamExt;
+use std::collections::{HashMap, VecDeque};
+use std::io;
 use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::{self, Instant, MissedTickBehavior};
 use tui::style::Color;
 
-#[derive(Debug)]
-pub enum GameMode {
-    Host(TcpStream, String),
-    Client(TcpStream, String),
-    Offline,
-}
+// Key presses beyond this, within the same few ticks, are dropped
+const MAX_QUEUED_DIRECTIONS: usize = 3;
 
-pub enum StartPosition {
-    North,
-    West,
-    South,
-    East,
+pub enum GameMode {
+    Host(Transport, String),
+    Client(Transport, String),
+    /// With a seed, the AIs make the same random choices every time
+    Offline(Option<u64>),
 }
 
-impl StartPosition {
-    fn resolve(&self, size: (u16, u16)) -> (Point, Direction) {
-        match self {
-            StartPosition::North => (((size.0 / 2) as i32, 0), DOWN),
-            StartPosition::West => ((0, (size.1 / 2) as i32), RIGHT),
-            StartPosition::South => (((size.0 / 2) as i32, (size.1 - 1) as i32), UP),
-            StartPosition::East => (((size.0 - 1) as i32, (size.1 / 2) as i32), LEFT),
-        }
-    }
-
-    fn direction(&self) -> Direction {
-        match self {
-            StartPosition::North => DOWN,
-            StartPosition::West => RIGHT,
-            StartPosition::South => UP,
-            StartPosition::East => LEFT,
-        }
-    }
-}
+// Colors for players that haven't been given one, in the order they are handed out
+const PLAYER_COLORS: [PlayerColor; MAX_SEATS] = [
+    PlayerColor::Blue,
+    PlayerColor::Green,
+    PlayerColor::Magenta,
+    PlayerColor::Cyan,
+    PlayerColor::Yellow,
+    PlayerColor::Red,
+    PlayerColor::White,
+    PlayerColor::LightBlue,
+];
 
 pub struct App {
     game: Game,
     ui: TerminalUi,
     networking: Option<Networking>,
     players_controlled_by_keyboard: Vec<(KeyboardControls, PlayerIndex)>,
-    players_controlled_by_ai: Vec<PlayerIndex>,
+    players_controlled_by_ai: Vec<(Box<dyn AiStrategy>, PlayerIndex)>,
+    // Turns that local players have asked for, one of which is made per frame
+    queued_directions: Vec<VecDeque<Direction>>,
+    // Local players that have pressed their boost key since the last frame
+    queued_boosts: Vec<bool>,
+    chat_input: Option<String>,
+    start_positions: Vec<StartPosition>,
+    tick_duration: Duration,
+    round: u32,
+    remote_left: bool,
+    event_log: Option<EventLog>,
+    stats: Option<StatsRecorder>,
 }
 
 impl App {
-    pub fn new(mode: GameMode) -> anyhow::Result<Self> {
-        let suggested_game_size = (35, 16);
+    pub async fn new(mode: GameMode, settings: &Settings) -> anyhow::Result<Self> {
         let game_size;
-
-        let arrow_controls =
-            KeyboardControls::new([KeyCode::Up, KeyCode::Left, KeyCode::Down, KeyCode::Right]);
-        let wasd_controls = KeyboardControls::new([
-            KeyCode::Char('w'),
-            KeyCode::Char('a'),
-            KeyCode::Char('s'),
-            KeyCode::Char('d'),
-        ]);
+        let online_controls = KeyboardControls::new(settings.key_bindings[0]);
 
         let frame = 1;
 
         let networking;
         let players;
+        let tie_rule;
+        let power_ups;
+        let start_positions;
         let mut players_controlled_by_keyboard = vec![];
         let mut players_controlled_by_ai = vec![];
 
         match mode {
             GameMode::Host(socket, local_name) => {
-                game_size = suggested_game_size;
+                game_size = settings.game_size;
+                tie_rule = settings.tie_rule;
+                power_ups = settings.power_ups;
+                start_positions = StartPosition::spread(2);
                 let local_player = Player::new(
                     local_name.clone(),
-                    Color::Blue,
-                    StartPosition::West.resolve(game_size),
+                    PlayerColor::Blue,
+                    start_positions[0].resolve(game_size),
                 );
 
                 let local_player_i = 0;
                 let remote_player_i = 1;
-                players_controlled_by_keyboard.push((wasd_controls, local_player_i));
+                players_controlled_by_keyboard.push((online_controls, local_player_i));
                 let (n, game_info) = Networking::host(
                     socket,
                     local_player_i,
                     remote_player_i,
                     local_player.direction,
                     frame,
-                    game_size,
+                    Rules {
+                        size: game_size,
+                        tie_rule,
+                        power_ups,
+                    },
                     local_name,
-                );
+                )
+                .await?;
                 networking = Some(n);
 
                 let remote_player = Player::new(
                     game_info.remote_player_name,
-                    Color::Green,
-                    StartPosition::East.resolve(game_size),
+                    PlayerColor::Green,
+                    start_positions[1].resolve(game_size),
                 );
                 players = vec![local_player, remote_player];
             }
             GameMode::Client(socket, local_name) => {
                 let remote_player_i = 0;
                 let local_player_i = 1;
-                players_controlled_by_keyboard.push((wasd_controls, local_player_i));
-                let local_start_pos = StartPosition::East;
+                players_controlled_by_keyboard.push((online_controls, local_player_i));
+                start_positions = StartPosition::spread(2);
+                let remote_start_pos = start_positions[remote_player_i];
+                let local_start_pos = start_positions[local_player_i];
                 let (n, game_info) = Networking::join(
                     socket,
                     local_player_i,
@@ -119,56 +128,107 @@ impl App {
                     local_start_pos.direction(),
                     frame,
                     local_name.clone(),
-                );
+                )
+                .await?;
                 networking = Some(n);
-                game_size = game_info.size;
-                let remote_start_pos = StartPosition::West;
+                game_size = game_info.rules.size;
+                tie_rule = game_info.rules.tie_rule;
+                power_ups = game_info.rules.power_ups;
 
                 players = vec![
                     Player::new(
                         game_info.remote_player_name,
-                        Color::Blue,
+                        PlayerColor::Blue,
                         remote_start_pos.resolve(game_size),
                     ),
-                    Player::new(local_name, Color::Green, local_start_pos.resolve(game_size)),
-                ];
-            }
-            GameMode::Offline => {
-                game_size = suggested_game_size;
-                players = vec![
-                    Player::new(
-                        "Player 1".to_string(),
-                        Color::Blue,
-                        StartPosition::West.resolve(game_size),
-                    ),
                     Player::new(
-                        "Player 2".to_string(),
-                        Color::Green,
-                        StartPosition::East.resolve(game_size),
-                    ),
-                    Player::new(
-                        "AI 1".to_string(),
-                        Color::Magenta,
-                        StartPosition::North.resolve(game_size),
-                    ),
-                    Player::new(
-                        "AI 2".to_string(),
-                        Color::Cyan,
-                        StartPosition::South.resolve(game_size),
+                        local_name,
+                        PlayerColor::Green,
+                        local_start_pos.resolve(game_size),
                     ),
                 ];
-                players_controlled_by_keyboard.push((wasd_controls, 0));
-                players_controlled_by_keyboard.push((arrow_controls, 1));
-                players_controlled_by_ai.push(2);
-                players_controlled_by_ai.push(3);
+            }
+            GameMode::Offline(seed) => {
+                game_size = settings.game_size;
+                tie_rule = settings.tie_rule;
+                power_ups = settings.power_ups;
+                let seats: Vec<Seat> = settings
+                    .seats
+                    .iter()
+                    .filter(|seat| **seat != Seat::Empty)
+                    .cloned()
+                    .collect();
+                start_positions = StartPosition::spread(seats.len());
+
+                let mut free_colors = PLAYER_COLORS
+                    .into_iter()
+                    .filter(|color| !settings.colors.contains(color));
+                // The AIs think one after another, and must leave most of the tick for the rest
+                let ai_seats = seats
+                    .iter()
+                    .filter(|s| matches!(s, Seat::Ai { .. } | Seat::Bot { .. }))
+                    .count();
+                let ai_time_budget = settings.tick_duration / 3 / ai_seats.max(1) as u32;
+                let mut keyboard_count = 0;
+                let mut ai_count = 0;
+                let mut bot_count = 0;
+                let mut offline_players = vec![];
+                for (i, seat) in seats.into_iter().enumerate() {
+                    let default_name = match seat {
+                        Seat::Keyboard { keys } => {
+                            let controls = KeyboardControls::new(settings.key_bindings[keys]);
+                            players_controlled_by_keyboard.push((controls, i));
+                            keyboard_count += 1;
+                            format!("Player {}", keyboard_count)
+                        }
+                        Seat::Ai { strategy } => {
+                            let ai = match seed {
+                                Some(seed) => strategy
+                                    .create_seeded(ai_time_budget, seed.wrapping_add(i as u64)),
+                                None => strategy.create(ai_time_budget),
+                            };
+                            players_controlled_by_ai.push((ai, i));
+                            ai_count += 1;
+                            format!("AI {}", ai_count)
+                        }
+                        Seat::Bot { command } => {
+                            let bot = ExternalBot::spawn(&command, ai_time_budget);
+                            players_controlled_by_ai.push((Box::new(bot), i));
+                            bot_count += 1;
+                            format!("Bot {}", bot_count)
+                        }
+                        Seat::Empty => unreachable!("Empty seats have been filtered out"),
+                    };
+                    let color = match settings.colors.get(i) {
+                        Some(color) => *color,
+                        None => free_colors.next().unwrap_or(PlayerColor::Gray),
+                    };
+                    let mut player = Player::new(
+                        settings.names.get(i).cloned().unwrap_or(default_name),
+                        color,
+                        start_positions[i].resolve(game_size),
+                    );
+                    if let Some(speed) = settings.speeds.get(i) {
+                        player.base_speed = *speed;
+                    }
+                    offline_players.push(player);
+                }
+                players = offline_players;
                 networking = None;
             }
         };
 
         let mut ui = TerminalUi::new(game_size, players.clone());
         ui.set_banner(Color::Yellow, "Go!");
+        if networking.is_some() {
+            ui.enable_chat();
+        }
 
-        let game = Game::new(game_size, players, frame);
+        let queued_directions = vec![VecDeque::new(); players.len()];
+        let queued_boosts = vec![false; players.len()];
+        let mut game = Game::new(game_size, players, frame);
+        game.tie_rule = tie_rule;
+        game.spawn_power_ups = power_ups;
 
         Ok(Self {
             game,
@@ -176,34 +236,118 @@ impl App {
             ui,
             players_controlled_by_keyboard,
             players_controlled_by_ai,
+            queued_directions,
+            queued_boosts,
+            chat_input: None,
+            start_positions,
+            tick_duration: settings.tick_duration,
+            round: 1,
+            remote_left: false,
+            event_log: None,
+            stats: None,
         })
     }
 
-    pub fn run(&mut self) -> anyhow::Result<()> {
-        let (sender, receiver) = mpsc::channel();
-        Self::spawn_clock(sender.clone());
-        Self::spawn_input_listener(sender.clone());
+    /// Streams the game's events to `log` from now on, starting with the current round
+    pub fn stream_events(&mut self, mut log: EventLog) -> io::Result<()> {
+        log.round_start(&self.game, self.round, self.mode_name())?;
+        self.event_log = Some(log);
+        Ok(())
+    }
+
+    /// Records every round that is played to the end from now on, starting with the current one
+    pub fn record_stats(&mut self, mut stats: StatsRecorder) {
+        stats.round_start(&self.game, self.mode_name());
+        self.stats = Some(stats);
+        self.update_leaderboard();
+    }
 
+    // The leaderboard screen is about the players in the game
+    fn update_leaderboard(&mut self) {
+        if let Some(stats) = &self.stats {
+            let names: Vec<String> = self.game.players.iter().map(|p| p.name.clone()).collect();
+            self.ui.set_leaderboard(stats.leaderboard(&names));
+        }
+    }
+
+    fn mode_name(&self) -> &'static str {
+        match &self.networking {
+            None => "offline",
+            Some(networking) if networking.local_player_index() == 0 => "host",
+            Some(_) => "client",
+        }
+    }
+
+    // Whatever reads the events going away shouldn't stop the game, so the stream is closed
+    fn log_events(&mut self, write: impl FnOnce(&mut EventLog, &Game) -> io::Result<()>) {
+        if let Some(log) = &mut self.event_log {
+            if let Err(error) = write(log, &self.game) {
+                self.event_log = None;
+                self.ui
+                    .set_banner(Color::Yellow, &format!("Event stream closed: {}", error));
+            }
+        }
+    }
+
+    pub async fn run(&mut self) -> anyhow::Result<()> {
+        let mut clock = time::interval_at(Instant::now() + self.tick_duration, self.tick_duration);
+        clock.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut user_input = EventStream::new();
+
+        let mut network_events = None;
         if let Some(networking) = &mut self.networking {
-            let result = networking.start_game(sender);
-            self.handle_net_result(result);
+            let (events, outcomes) = networking.start_game();
+            network_events = Some(events);
+            self.execute_net_outcomes(outcomes);
         }
 
         loop {
             self.ui.draw()?;
 
-            match receiver.recv()? {
-                ThreadMessage::UserInput(event) => match event {
+            let message = tokio::select! {
+                _ = clock.tick() => Message::Tick,
+                Some(event) = user_input.next() => Message::UserInput(event?),
+                Some(event) = next_network_event(&mut network_events) => Message::Network(event),
+            };
+
+            match message {
+                Message::UserInput(event) => match event {
                     Key(KeyEvent {
                         code: KeyCode::Char('c'),
                         modifiers: KeyModifiers::CONTROL,
                         kind: KeyEventKind::Press,
                         state: _,
                     }) => break,
+                    Key(key) if self.chat_input.is_some() => self.handle_chat_input(key),
+                    Key(KeyEvent {
+                        code: KeyCode::Enter,
+                        kind: KeyEventKind::Press,
+                        ..
+                    }) if self.networking.is_some() => {
+                        self.chat_input = Some(String::new());
+                        self.ui.set_chat_input(self.chat_input.clone());
+                    }
                     Key(KeyEvent {
                         code: KeyCode::Char('q'),
                         ..
                     }) => break,
+                    Key(KeyEvent {
+                        code: KeyCode::Char(key @ ('r' | 'R')),
+                        kind: KeyEventKind::Press,
+                        ..
+                    }) if self.game.game_over && !self.remote_left => {
+                        self.request_rematch(key == 'R')
+                    }
+                    Key(KeyEvent {
+                        code: KeyCode::F(3),
+                        kind: KeyEventKind::Press,
+                        ..
+                    }) => self.ui.toggle_net_stats(),
+                    Key(KeyEvent {
+                        code: KeyCode::F(2),
+                        kind: KeyEventKind::Press,
+                        ..
+                    }) => self.ui.toggle_leaderboard(),
                     Key(KeyEvent {
                         code,
                         kind: KeyEventKind::Press,
@@ -213,13 +357,19 @@ impl App {
                             let (controls, player_i) = &self.players_controlled_by_keyboard[i];
                             let player_i = *player_i;
                             let player = &self.game.players[player_i];
-                            if !player.crashed {
-                                if let Some(direction) = controls.handle(code) {
-                                    if let Some(networking) = &mut self.networking {
-                                        let result = networking.set_direction(direction);
-                                        self.handle_net_result(result);
-                                    } else {
-                                        self.game.players[player_i].direction = direction;
+                            if !player.crashed && !self.game.game_over && controls.is_boost(code) {
+                                self.queued_boosts[player_i] = true;
+                            } else if !player.crashed && !self.game.game_over {
+                                // Relative controls turn from where the player is going to be
+                                // heading once the earlier key presses have been applied
+                                let queue = &mut self.queued_directions[player_i];
+                                let heading = queue.back().copied().unwrap_or(player.direction);
+                                if let Some(direction) = controls.handle(code, heading) {
+                                    if direction != heading
+                                        && !game::is_reversal(heading, direction)
+                                        && queue.len() < MAX_QUEUED_DIRECTIONS
+                                    {
+                                        queue.push_back(direction);
                                     }
                                 }
                             }
@@ -228,23 +378,21 @@ impl App {
                     _ => {}
                 },
 
-                ThreadMessage::Network(event) => match event {
-                    NetworkEvent::BufferedOutcomes => {
-                        let networking = self.networking.as_mut().unwrap();
-                        let outcomes = networking.take_buffered_outcomes();
-                        self.execute_net_outcomes(outcomes);
-                    }
+                Message::Network(event) => {
+                    let networking = self.networking.as_mut().unwrap();
+                    let result = networking.handle_event(event);
+                    self.handle_net_result(result)?;
+                }
 
-                    NetworkEvent::ReceiveError(e) => {
-                        panic!("Failed to receive from socket: {:?}", e);
+                Message::Tick => {
+                    if let Some(networking) = &self.networking {
+                        self.ui.set_net_stats(networking.stats());
                     }
-                },
-
-                ThreadMessage::Tick => {
                     if !self.game.game_over {
+                        self.apply_queued_directions();
                         if let Some(networking) = self.networking.as_mut() {
-                            let result = networking.commit_frame();
-                            self.handle_net_result(result);
+                            let outcomes = networking.commit_frame();
+                            self.execute_net_outcomes(outcomes);
                         } else {
                             self.run_frame();
                         }
@@ -253,35 +401,179 @@ impl App {
             }
         }
 
-        if let Some(networking) = &mut self.networking {
-            networking.exit();
+        if let Some(networking) = self.networking.take() {
+            networking.exit().await;
         }
 
         Ok(())
     }
 
-    fn handle_net_result(&mut self, result: NetResult<Vec<Outcome>>) {
-        match result {
-            Ok(outcomes) => {
-                self.execute_net_outcomes(outcomes);
+    // Makes at most one queued turn per local player, right before the frame is run (offline)
+    // or committed (online). While an online frame is stuck waiting on the remote, the queue is
+    // left alone, so that no key presses are lost.
+    fn apply_queued_directions(&mut self) {
+        if let Some(networking) = &self.networking {
+            if !networking.is_frame_open() {
+                return;
             }
-            Err(error) => {
-                panic!("Unexpected networking error: {:?}", error);
+        }
+        for i in 0..self.players_controlled_by_keyboard.len() {
+            let player_i = self.players_controlled_by_keyboard[i].1;
+            if self.game.players[player_i].crashed {
+                continue;
             }
+            if let Some(direction) = self.queued_directions[player_i].pop_front() {
+                if let Some(networking) = &mut self.networking {
+                    // A turn that the game would turn down isn't sent to the remote either
+                    if self.game.players[player_i].can_steer(direction) {
+                        let outcomes = networking.set_direction(direction);
+                        self.execute_net_outcomes(outcomes);
+                    }
+                } else {
+                    self.game.players[player_i].steer(direction);
+                }
+            }
+            // A boost that is still cooling down is dropped, rather than made later
+            if std::mem::take(&mut self.queued_boosts[player_i])
+                && self.game.players[player_i].can_boost()
+            {
+                if let Some(networking) = &mut self.networking {
+                    let outcomes = networking.boost();
+                    self.execute_net_outcomes(outcomes);
+                } else {
+                    self.game.players[player_i].boost();
+                }
+            }
+        }
+    }
+
+    fn request_rematch(&mut self, swap_sides: bool) {
+        if let Some(networking) = &mut self.networking {
+            let outcomes = networking.request_rematch(swap_sides);
+            self.ui.set_banner(Color::Yellow, "Waiting for rematch...");
+            self.execute_net_outcomes(outcomes);
+        } else {
+            self.start_new_round(swap_sides, 1);
         }
     }
 
+    fn start_new_round(&mut self, swap_sides: bool, frame: u32) {
+        if swap_sides {
+            self.start_positions.reverse();
+        }
+        self.round += 1;
+
+        let game_size = self.game.size();
+        let players: Vec<Player> = self
+            .game
+            .players
+            .iter()
+            .zip(&self.start_positions)
+            .map(|(player, start_position)| {
+                let mut new_player = Player::new(
+                    player.name.clone(),
+                    player.color,
+                    start_position.resolve(game_size),
+                );
+                new_player.base_speed = player.base_speed;
+                new_player
+            })
+            .collect();
+
+        self.ui.start_round(self.round, players.clone());
+        self.ui.set_banner(Color::Yellow, "Go!");
+        let (tie_rule, power_ups) = (self.game.tie_rule, self.game.spawn_power_ups);
+        self.game = Game::new(game_size, players, frame);
+        self.game.tie_rule = tie_rule;
+        self.game.spawn_power_ups = power_ups;
+        self.ui.set_power_ups(&self.game.power_ups);
+        self.queued_directions.iter_mut().for_each(VecDeque::clear);
+        self.queued_boosts
+            .iter_mut()
+            .for_each(|boost| *boost = false);
+        let (round, mode) = (self.round, self.mode_name());
+        self.log_events(|log, game| log.round_start(game, round, mode));
+        if let Some(stats) = &mut self.stats {
+            stats.round_start(&self.game, mode);
+        }
+
+        if let Some(networking) = &mut self.networking {
+            let direction = self.game.players[networking.local_player_index()].direction;
+            let outcomes = networking.restart_game(direction);
+            self.execute_net_outcomes(outcomes);
+        }
+    }
+
+    fn handle_chat_input(&mut self, key: KeyEvent) {
+        if key.kind != KeyEventKind::Press {
+            return;
+        }
+        let input = self.chat_input.as_mut().unwrap();
+        match key.code {
+            KeyCode::Enter => {
+                let text = self.chat_input.take().unwrap();
+                if !text.trim().is_empty() {
+                    let networking = self.networking.as_mut().unwrap();
+                    let player_i = networking.local_player_index();
+                    let outcomes = networking.send_chat(&text);
+                    self.ui.add_chat_message(player_i, text);
+                    self.execute_net_outcomes(outcomes);
+                }
+            }
+            KeyCode::Esc => self.chat_input = None,
+            KeyCode::Backspace => {
+                input.pop();
+            }
+            KeyCode::Char(c) if input.len() + c.len_utf8() <= MAX_CHAT_MESSAGE_LEN => {
+                input.push(c);
+            }
+            _ => {}
+        }
+        self.ui.set_chat_input(self.chat_input.clone());
+    }
+
+    // An error ends the game, as the remote has been left behind or is out of sync
+    fn handle_net_result(&mut self, result: NetResult<Vec<Outcome>>) -> anyhow::Result<()> {
+        let outcomes = result.context("Network error")?;
+        self.execute_net_outcomes(outcomes);
+        Ok(())
+    }
+
     fn execute_net_outcomes(&mut self, outcomes: Vec<Outcome>) {
         for outcome in outcomes {
             match outcome {
                 Outcome::PlayerControl(control) => {
-                    self.game.players[control.player_i].direction = control.direction;
+                    self.game.players[control.player_i].steer(control.direction);
+                }
+                Outcome::PlayerBoost(player_i) => {
+                    self.game.players[player_i].boost();
                 }
                 Outcome::RunFrame => {
                     self.run_frame();
                     let networking = self.networking.as_mut().unwrap();
-                    let result = networking.start_new_frame(self.game.frame);
-                    self.handle_net_result(result);
+                    if self.game.game_over {
+                        networking.end_round();
+                    }
+                    let player_i = networking.local_player_index();
+                    let direction = self.game.players[player_i].direction;
+                    let outcomes = networking.start_new_frame(self.game.frame, direction);
+                    self.execute_net_outcomes(outcomes);
+                }
+                Outcome::Chat { player_i, text } => {
+                    self.ui.add_chat_message(player_i, text);
+                }
+                Outcome::RemoteWantsRematch { swap_sides } => {
+                    let networking = self.networking.as_ref().unwrap();
+                    let player_i = networking.remote_player_index();
+                    let key = if swap_sides { "R" } else { "r" };
+                    let msg = format!(
+                        "{} wants a rematch! [{}]",
+                        self.game.players[player_i].name, key
+                    );
+                    self.ui.set_banner(Color::Yellow, &msg);
+                }
+                Outcome::Rematch { swap_sides, frame } => {
+                    self.start_new_round(swap_sides, frame);
                 }
                 Outcome::RemoteLeft { politely } => {
                     let networking = self.networking.as_ref().unwrap();
@@ -293,6 +585,8 @@ impl App {
                     };
                     self.ui.set_banner(Color::Yellow, &msg);
                     self.game.game_over = true;
+                    self.remote_left = true;
+                    self.log_events(EventLog::remote_left);
                 }
             }
         }
@@ -300,84 +594,43 @@ impl App {
 
     fn run_frame(&mut self) {
         let frame_events = self.game.run_frame();
-
-        for i in 0..self.game.players.len() {
-            self.ui.set_player_line(i, &self.game.players[i].line);
-            self.ui
-                .set_player_direction(i, self.game.players[i].direction);
-        }
-
-        for event in frame_events {
-            match event {
-                FrameEvent::PlayerCrashed(i) => {
-                    self.ui.set_banner(
-                        Color::Yellow,
-                        &format!("{} crashed!", self.game.players[i].name),
-                    );
-                    self.ui.set_player_crashed(i, true);
-                }
-                FrameEvent::PlayerWon(color, name) => {
-                    self.ui.set_banner(color, &format!("{} won!", name));
-                }
-                FrameEvent::EveryoneCrashed => {
-                    self.ui.set_banner(Color::Yellow, "Everyone crashed!");
-                    for i in 0..self.game.players.len() {
-                        self.ui.set_player_crashed(i, true);
-                    }
-                }
+        self.log_events(|log, game| log.frame(game, &frame_events));
+        self.ui.show_frame(&self.game, &frame_events);
+
+        if let Some(stats) = &mut self.stats {
+            match stats.frame(&self.game, &frame_events) {
+                Ok(()) if self.game.game_over => self.update_leaderboard(),
+                Ok(()) => {}
+                Err(error) => self.ui.set_banner(Color::Yellow, &format!("{:#}", error)),
             }
         }
 
-        for i in 0..self.game.players.len() {
-            self.ui.set_player_score(i, self.game.players[i].score);
-        }
-
-        for i in 0..self.players_controlled_by_ai.len() {
-            let player_i = self.players_controlled_by_ai[i];
+        for (strategy, player_i) in &mut self.players_controlled_by_ai {
+            let player_i = *player_i;
             if !self.game.players[player_i].crashed {
-                self.run_player_ai(player_i)
-            }
-        }
-    }
-
-    fn run_player_ai(&mut self, player_index: PlayerIndex) {
-        let ai_head = self.game.players[player_index].head();
-        if !self.game.is_vacant(game::translated(
-            ai_head,
-            self.game.players[player_index].direction,
-        )) {
-            for dir in DIRECTIONS {
-                if self.game.is_vacant(game::translated(ai_head, dir)) {
-                    self.game.players[player_index].direction = dir;
-                    break;
+                let direction = strategy.choose_direction(&self.game, player_i);
+                self.game.players[player_i].steer(direction);
+                if let Some(problem) = strategy.take_problem() {
+                    let name = &self.game.players[player_i].name;
+                    self.ui
+                        .set_banner(Color::Yellow, &format!("{}: {}", name, problem));
                 }
             }
         }
     }
+}
 
-    fn spawn_clock(sender: Sender<ThreadMessage>) {
-        thread::spawn(move || loop {
-            thread::sleep(Duration::from_millis(120));
-            if sender.send(ThreadMessage::Tick).is_err() {
-                // no receiver (i.e. main thread has exited)
-                break;
-            }
-        });
-    }
-
-    fn spawn_input_listener(sender: Sender<ThreadMessage>) {
-        thread::spawn(move || loop {
-            let event = crossterm::event::read().expect("Receiving event");
-            if sender.send(ThreadMessage::UserInput(event)).is_err() {
-                // no receiver (i.e. main thread has exited)
-                break;
-            }
-        });
+async fn next_network_event(
+    events: &mut Option<UnboundedReceiver<NetworkEvent>>,
+) -> Option<NetworkEvent> {
+    match events {
+        Some(events) => events.recv().await,
+        None => std::future::pending().await,
     }
 }
 
 #[derive(Debug)]
-pub enum ThreadMessage {
+enum Message {
     UserInput(Event),
     Network(NetworkEvent),
     Tick,
@@ -385,20 +638,82 @@ pub enum ThreadMessage {
 
 #[derive(Clone)]
 struct KeyboardControls {
-    map: HashMap<KeyCode, Direction>,
+    steering: Steering,
+    boost: Option<config::Key>,
+}
+
+#[derive(Clone)]
+enum Steering {
+    Absolute(HashMap<config::Key, Direction>),
+    Relative {
+        turn_left: config::Key,
+        turn_right: config::Key,
+    },
 }
 
 impl KeyboardControls {
-    fn new(direction_keys: [KeyCode; 4]) -> Self {
-        let mut map = HashMap::new();
-        map.insert(direction_keys[0], UP);
-        map.insert(direction_keys[1], LEFT);
-        map.insert(direction_keys[2], DOWN);
-        map.insert(direction_keys[3], RIGHT);
-        Self { map }
+    fn new(binding: KeyBinding) -> Self {
+        let steering = match binding {
+            KeyBinding::Absolute {
+                keys: direction_keys,
+                ..
+            } => {
+                let mut map = HashMap::new();
+                map.insert(direction_keys[0], UP);
+                map.insert(direction_keys[1], LEFT);
+                map.insert(direction_keys[2], DOWN);
+                map.insert(direction_keys[3], RIGHT);
+                Steering::Absolute(map)
+            }
+            KeyBinding::Relative {
+                turn_left,
+                turn_right,
+                ..
+            } => Steering::Relative {
+                turn_left,
+                turn_right,
+            },
+        };
+        Self {
+            steering,
+            boost: binding.boost(),
+        }
+    }
+
+    // Relative controls turn from the heading that the player has in the current frame
+    fn handle(&self, pressed_key_code: KeyCode, heading: Direction) -> Option<Direction> {
+        let pressed = steering_key(pressed_key_code)?;
+        match &self.steering {
+            Steering::Absolute(map) => map.get(&pressed).copied(),
+            Steering::Relative { turn_left, .. } if pressed == *turn_left => {
+                Some(game::turned_left(heading))
+            }
+            Steering::Relative { turn_right, .. } if pressed == *turn_right => {
+                Some(game::turned_right(heading))
+            }
+            Steering::Relative { .. } => None,
+        }
     }
 
-    fn handle(&self, pressed_key_code: KeyCode) -> Option<Direction> {
-        self.map.get(&pressed_key_code).copied()
+    fn is_boost(&self, pressed_key_code: KeyCode) -> bool {
+        self.boost.is_some() && steering_key(pressed_key_code) == self.boost
     }
 }
+
+fn steering_key(code: KeyCode) -> Option<config::Key> {
+    use config::Key;
+    let key = match code {
+        KeyCode::Char(c) => Key::Char(c),
+        KeyCode::Up => Key::Up,
+        KeyCode::Left => Key::Left,
+        KeyCode::Down => Key::Down,
+        KeyCode::Right => Key::Right,
+        KeyCode::Home => Key::Home,
+        KeyCode::End => Key::End,
+        KeyCode::PageUp => Key::PageUp,
+        KeyCode::PageDown => Key::PageDown,
+        KeyCode::Insert => Key::Insert,
+        _ => return None,
+    };
+    Some(key)
+}
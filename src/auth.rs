@@ -0,0 +1,132 @@
+use crate::net::{with_timeout, NetResult};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// A connecting client gets this long to answer the challenge before the host hangs up on it
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const NONCE_LEN: usize = 32;
+const RESPONSE_LEN: usize = 32;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AuthOutcome {
+    Accepted,
+    Rejected,
+    PasswordRequired,
+}
+
+/// The host side of the handshake that precedes `Networking::host`. Returns whether the client
+/// proved that it knows the password. The password itself never goes over the wire; the client
+/// answers a random challenge with an HMAC keyed by the password.
+pub async fn accept_client(socket: &mut TcpStream, password: Option<&str>) -> NetResult<bool> {
+    let password = match password {
+        Some(password) => password,
+        None => {
+            ChallengePacket(None).write(socket).await?;
+            return Ok(true);
+        }
+    };
+
+    let mut nonce = [0; NONCE_LEN];
+    getrandom::getrandom(&mut nonce)?;
+    ChallengePacket(Some(nonce)).write(socket).await?;
+
+    let response = with_timeout(RESPONSE_TIMEOUT, ResponsePacket::read(socket)).await?;
+
+    let accepted = match response {
+        ResponsePacket(Some(response)) => mac(password, &nonce).verify_slice(&response).is_ok(),
+        ResponsePacket(None) => false,
+    };
+    socket.write_all(&[accepted as u8]).await?;
+    Ok(accepted)
+}
+
+/// The client side of the handshake that precedes `Networking::join`.
+pub async fn authenticate(
+    socket: &mut TcpStream,
+    password: Option<&str>,
+) -> NetResult<AuthOutcome> {
+    let nonce = match ChallengePacket::read(socket).await?.0 {
+        Some(nonce) => nonce,
+        None => return Ok(AuthOutcome::Accepted),
+    };
+
+    let response = password.map(|password| mac(password, &nonce).finalize().into_bytes().into());
+    ResponsePacket(response).write(socket).await?;
+    if response.is_none() {
+        return Ok(AuthOutcome::PasswordRequired);
+    }
+
+    let mut accepted = [0];
+    socket.read_exact(&mut accepted).await?;
+    if accepted[0] == 1 {
+        Ok(AuthOutcome::Accepted)
+    } else {
+        Ok(AuthOutcome::Rejected)
+    }
+}
+
+fn mac(password: &str, nonce: &[u8; NONCE_LEN]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(password.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(b"achtung-auth");
+    mac.update(nonce);
+    mac
+}
+
+// 0 = no password needed
+// 1 <32 bytes of nonce>
+struct ChallengePacket(Option<[u8; NONCE_LEN]>);
+
+impl ChallengePacket {
+    async fn read(socket: &mut TcpStream) -> NetResult<Self> {
+        let mut kind = [0];
+        socket.read_exact(&mut kind).await?;
+        if kind[0] == 0 {
+            return Ok(Self(None));
+        }
+        let mut nonce = [0; NONCE_LEN];
+        socket.read_exact(&mut nonce).await?;
+        Ok(Self(Some(nonce)))
+    }
+
+    async fn write(&self, socket: &mut TcpStream) -> NetResult<()> {
+        match &self.0 {
+            None => socket.write_all(&[0]).await,
+            Some(nonce) => {
+                socket.write_all(&[1]).await?;
+                socket.write_all(nonce).await
+            }
+        }
+    }
+}
+
+// 0 = the client has no password
+// 1 <32 bytes of HMAC-SHA256(password, "achtung-auth" + nonce)>
+struct ResponsePacket(Option<[u8; RESPONSE_LEN]>);
+
+impl ResponsePacket {
+    async fn read(socket: &mut TcpStream) -> NetResult<Self> {
+        let mut kind = [0];
+        socket.read_exact(&mut kind).await?;
+        if kind[0] == 0 {
+            return Ok(Self(None));
+        }
+        let mut response = [0; RESPONSE_LEN];
+        socket.read_exact(&mut response).await?;
+        Ok(Self(Some(response)))
+    }
+
+    async fn write(&self, socket: &mut TcpStream) -> NetResult<()> {
+        match &self.0 {
+            None => socket.write_all(&[0]).await,
+            Some(response) => {
+                socket.write_all(&[1]).await?;
+                socket.write_all(response).await
+            }
+        }
+    }
+}
@@ -0,0 +1,351 @@
+use crate::ai::AiStrategy;
+use crate::game::{direction_name, parse_direction, Direction, Game, PlayerIndex};
+use crate::Point;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// An AI seat that is played by a child process, so that bots can be written in any language.
+///
+/// The process is sent one JSON object per line on stdin: a `start` message at the beginning of
+/// every round, with everyone's full trail, and then a `frame` message every frame with where
+/// each player's head went and the cells it moved through. It answers each one with a line like
+/// `{"direction": "up"}` on stdout. If no answer comes within the time budget, the player keeps
+/// going in the direction it has, and an answer that comes after that is dropped. Anything that
+/// goes wrong is reported through `take_problem` rather than ending the game.
+pub struct ExternalBot {
+    process: Option<BotProcess>,
+    last_frame: Option<u32>,
+    time_budget: Duration,
+    problem: Option<String>,
+}
+
+// Messages are written and answers read on threads of their own, so that a process that stops
+// reading or writing can't hold up the game
+struct BotProcess {
+    child: Child,
+    messages: SyncSender<String>,
+    answers: Receiver<String>,
+    // The last line the process wrote to stderr, to explain why it died
+    last_error: Arc<Mutex<Option<String>>>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum BotMessage<'a> {
+    Start {
+        frame: u32,
+        size: (u16, u16),
+        you: PlayerIndex,
+        players: Vec<StartPlayer<'a>>,
+    },
+    Frame {
+        frame: u32,
+        players: Vec<FramePlayer<'a>>,
+    },
+}
+
+#[derive(Serialize)]
+struct StartPlayer<'a> {
+    name: &'a str,
+    trail: &'a [Point],
+    direction: &'static str,
+    crashed: bool,
+}
+
+#[derive(Serialize)]
+struct FramePlayer<'a> {
+    head: Point,
+    // Every cell moved into in the frame, as a fast player can skip past one
+    moved: &'a [Point],
+    speed: u32,
+    direction: &'static str,
+    crashed: bool,
+}
+
+#[derive(Deserialize)]
+struct BotAnswer {
+    direction: String,
+    // Lets the bot say which frame it's answering, so that a late answer isn't used for the
+    // wrong frame
+    frame: Option<u32>,
+}
+
+// Messages that can be waiting for the process to read them before it counts as stuck
+const MAX_UNREAD_MESSAGES: usize = 16;
+
+impl ExternalBot {
+    pub fn spawn(command: &str, time_budget: Duration) -> Self {
+        let mut bot = Self {
+            process: None,
+            last_frame: None,
+            time_budget,
+            problem: None,
+        };
+        match BotProcess::spawn(command) {
+            Ok(process) => bot.process = Some(process),
+            Err(error) => bot.report(format!("couldn't start '{}': {}", command, error)),
+        }
+        bot
+    }
+
+    fn report(&mut self, problem: String) {
+        self.problem = Some(problem);
+    }
+
+    fn message<'a>(&self, game: &'a Game, player_i: PlayerIndex) -> BotMessage<'a> {
+        let new_round = match self.last_frame {
+            Some(last_frame) => game.frame <= last_frame,
+            None => true,
+        };
+        if new_round {
+            BotMessage::Start {
+                frame: game.frame,
+                size: game.size(),
+                you: player_i,
+                players: game
+                    .players
+                    .iter()
+                    .map(|p| StartPlayer {
+                        name: &p.name,
+                        trail: &p.line,
+                        direction: direction_name(p.direction),
+                        crashed: p.crashed,
+                    })
+                    .collect(),
+            }
+        } else {
+            BotMessage::Frame {
+                frame: game.frame,
+                players: game
+                    .players
+                    .iter()
+                    .map(|p| FramePlayer {
+                        head: p.head(),
+                        moved: p.moved_cells(),
+                        speed: p.speed(),
+                        direction: direction_name(p.direction),
+                        crashed: p.crashed,
+                    })
+                    .collect(),
+            }
+        }
+    }
+
+    // Why the process is gone, with its exit status and last words if there are any
+    fn describe_exit(process: &mut BotProcess) -> String {
+        let status = match process.child.try_wait() {
+            Ok(Some(status)) => format!("exited ({})", status),
+            _ => "closed its stdout".to_string(),
+        };
+        match process.last_error.lock().unwrap().take() {
+            Some(line) => format!("bot {}: {}", status, line),
+            None => format!("bot {}", status),
+        }
+    }
+}
+
+impl AiStrategy for ExternalBot {
+    fn choose_direction(&mut self, game: &Game, player_i: PlayerIndex) -> Direction {
+        let current = game.players[player_i].direction;
+        let message = serde_json::to_string(&self.message(game, player_i))
+            .expect("Bot messages can always be serialized");
+        self.last_frame = Some(game.frame);
+
+        let process = match &mut self.process {
+            Some(process) => process,
+            None => return current,
+        };
+        // Whatever is left is too late for the frames it answered, and it's dropped rather than
+        // taken for this one's answer, which would keep the bot a frame behind
+        while process.answers.try_recv().is_ok() {}
+        match process.messages.try_send(message) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.report("bot isn't reading its input".to_string());
+                return current;
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                let problem = Self::describe_exit(process);
+                self.process = None;
+                self.report(problem);
+                return current;
+            }
+        }
+
+        let deadline = Instant::now() + self.time_budget;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let line = match process.answers.recv_timeout(timeout) {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => {
+                    let millis = self.time_budget.as_millis();
+                    self.report(format!("no answer within {}ms", millis));
+                    return current;
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    let problem = Self::describe_exit(process);
+                    self.process = None;
+                    self.report(problem);
+                    return current;
+                }
+            };
+
+            let answer = match serde_json::from_str::<BotAnswer>(&line) {
+                Ok(answer) => answer,
+                Err(error) => {
+                    self.report(format!("invalid answer '{}': {}", line, error));
+                    return current;
+                }
+            };
+            if matches!(answer.frame, Some(frame) if frame != game.frame) {
+                // A late answer to an earlier frame
+                continue;
+            }
+            return match parse_direction(&answer.direction) {
+                Some(direction) => direction,
+                None => {
+                    self.report(format!("unknown direction '{}'", answer.direction));
+                    current
+                }
+            };
+        }
+    }
+
+    fn take_problem(&mut self) -> Option<String> {
+        self.problem.take()
+    }
+}
+
+impl Drop for ExternalBot {
+    fn drop(&mut self) {
+        if let Some(mut process) = self.process.take() {
+            if process.child.kill().is_ok() {
+                let _ = process.child.wait();
+            }
+        }
+    }
+}
+
+impl BotProcess {
+    // The command is split on whitespace; there is no shell involved, so no quoting either
+    fn spawn(command: &str) -> std::io::Result<Self> {
+        let mut words = command.split_whitespace();
+        let program = words.next().unwrap_or_default();
+        let mut child = Command::new(program)
+            .args(words)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let mut stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+
+        let (messages, receiver) = mpsc::sync_channel::<String>(MAX_UNREAD_MESSAGES);
+        thread::spawn(move || {
+            for message in receiver {
+                if writeln!(stdin, "{}", message)
+                    .and_then(|_| stdin.flush())
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        let (sender, answers) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                match line {
+                    Ok(line) if line.trim().is_empty() => {}
+                    Ok(line) => {
+                        if sender.send(line).is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+
+        let last_error = Arc::new(Mutex::new(None));
+        let last_error_writer = Arc::clone(&last_error);
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                if !line.trim().is_empty() {
+                    *last_error_writer.lock().unwrap() = Some(line);
+                }
+            }
+        });
+
+        Ok(Self {
+            child,
+            messages,
+            answers,
+            last_error,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{Player, PlayerColor, LEFT, RIGHT};
+    use std::fs;
+    use std::path::PathBuf;
+
+    // A bot that runs this shell script, which is deleted once the bot is done with it
+    fn bot(name: &str, script: &str, time_budget: Duration) -> (ExternalBot, PathBuf) {
+        let path = std::env::temp_dir().join(format!("achtung-{}-{}.sh", name, std::process::id()));
+        fs::write(&path, script).unwrap();
+        let bot = ExternalBot::spawn(&format!("sh {}", path.display()), time_budget);
+        (bot, path)
+    }
+
+    fn game(line: Vec<Point>) -> Game {
+        let mut player = Player::new(String::new(), PlayerColor::Blue, (line[0], RIGHT));
+        player.line = line;
+        Game::new((250, 100), vec![player], 1)
+    }
+
+    #[test]
+    fn a_late_answer_is_not_taken_for_the_next_frame() {
+        let script = "read line; sleep 0.3; echo '{\"direction\": \"up\"}'\n\
+                      while read line; do echo '{\"direction\": \"left\"}'; done\n";
+        let (mut bot, path) = bot("late", script, Duration::from_millis(100));
+        let mut game = game(vec![(5, 5)]);
+        assert_eq!(bot.choose_direction(&game, 0), RIGHT);
+        assert!(bot.take_problem().is_some());
+
+        thread::sleep(Duration::from_millis(400));
+        game.frame += 1;
+        assert_eq!(bot.choose_direction(&game, 0), LEFT);
+        drop(bot);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn a_bot_that_does_not_read_does_not_hold_up_the_game() {
+        let (mut bot, path) = bot("deaf", "sleep 10\n", Duration::from_millis(50));
+        // Every message starts a round and has the whole line in it, which is more than a pipe
+        // holds
+        let game = game((0..20_000).map(|i| (i % 250, i / 250)).collect());
+        let start = Instant::now();
+        for _ in 0..MAX_UNREAD_MESSAGES + 2 {
+            assert_eq!(bot.choose_direction(&game, 0), RIGHT);
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            bot.take_problem().as_deref(),
+            Some("bot isn't reading its input")
+        );
+        drop(bot);
+        fs::remove_file(path).unwrap();
+    }
+}
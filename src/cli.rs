@@ -0,0 +1,444 @@
+use achtung_rs::ai::AiKind;
+#[cfg(feature = "tui")]
+use achtung_rs::config::{parse_color, parse_seat, parse_speed, Seat, MAX_SEATS};
+use achtung_rs::config::{parse_size, parse_strategy, parse_tick, parse_tie_rule, Settings};
+#[cfg(feature = "tui")]
+use achtung_rs::events::EventTarget;
+#[cfg(feature = "tui")]
+use achtung_rs::game::PlayerColor;
+use achtung_rs::game::TieRule;
+use achtung_rs::net::Rules;
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+use std::time::Duration;
+
+const DEFAULT_ADDRESS: &str = "localhost:8000";
+
+/// A simplified version of `Achtung, die Kurve!` right in your terminal
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Config file to read instead of the one in the user's config dir
+    #[arg(long, global = true, env = "ACHTUNG_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Profile in the config file to use
+    #[arg(long, global = true)]
+    pub profile: Option<String>,
+
+    /// File that finished rounds are recorded in, instead of the one in the user's data dir
+    #[arg(long, global = true, env = "ACHTUNG_STATS")]
+    pub stats: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Play against friends on the same keyboard and against AIs (the default)
+    #[cfg(feature = "tui")]
+    Offline(OfflineArgs),
+    /// Wait for another player to join an online game
+    #[cfg(feature = "tui")]
+    Host(HostArgs),
+    /// Join an online game
+    #[cfg(feature = "tui")]
+    Client(ClientArgs),
+    /// Join or host an online game without a terminal UI, played from stdin, a script or an AI
+    Headless(Box<HeadlessArgs>),
+    /// Host online games without a terminal UI for one client after another, played by an AI
+    Server(Box<HeadlessHostArgs>),
+    /// Watch a round again from an event log written with --events
+    #[cfg(feature = "tui")]
+    Replay(ReplayArgs),
+    /// Play AI strategies against each other without a terminal UI, and rank them
+    Tournament(TournamentArgs),
+    /// Show the leaderboard of the recorded rounds: win rates, streaks and head-to-head records
+    Stats(StatsArgs),
+    /// Inspect the config file
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Print the effective settings of the profile, with the flags of `offline` applied, in the
+    /// config file format
+    Dump(DumpArgs),
+    /// Print where the config file is read from
+    Path,
+}
+
+#[cfg(feature = "tui")]
+#[derive(Debug, Default, Args)]
+pub struct OfflineArgs {
+    #[command(flatten)]
+    pub game: GameArgs,
+
+    #[command(flatten)]
+    pub seats: SeatArgs,
+
+    /// Stream the game's events as JSON lines to this file, or to a Unix socket as unix:PATH
+    #[arg(long = "events", value_parser = EventTarget::parse)]
+    pub events: Option<EventTarget>,
+
+    /// Seed for the random choices of the AIs, so that they make the same ones every time
+    #[arg(long)]
+    pub seed: Option<u64>,
+}
+
+#[cfg(feature = "tui")]
+#[derive(Debug, Default, Args)]
+pub struct SeatArgs {
+    /// Comma separated seats, each one keys:N (steered with key set N), ai, ai:STRATEGY,
+    /// bot:COMMAND (played by a child process, see the README) or empty. The AI strategies are
+    /// greedy (the default), random, flood-fill, wall-hugger, voronoi and minimax:easy|medium|hard.
+    /// [default: keys:1,keys:2,ai,ai]
+    #[arg(long, value_delimiter = ',', value_parser = parse_seat, conflicts_with_all = ["players", "ais"])]
+    pub seats: Option<Vec<Seat>>,
+
+    /// Number of players steering with the keyboard (WASD, then arrow keys), as a shorthand for
+    /// --seats
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=MAX_SEATS as i64))]
+    pub players: Option<u8>,
+
+    /// Number of AI players, seated after the keyboard players
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=MAX_SEATS as i64))]
+    pub ais: Option<u8>,
+
+    /// Comma separated player names, in seat order
+    #[arg(long, value_delimiter = ',')]
+    pub names: Option<Vec<String>>,
+
+    /// Comma separated player colors, in seat order
+    #[arg(long, value_delimiter = ',', value_parser = parse_color)]
+    pub colors: Option<Vec<PlayerColor>>,
+
+    /// Comma separated player speeds in percent of the normal one (10 to 200), in seat order,
+    /// e.g. 80 as a handicap for a strong player
+    #[arg(long, value_delimiter = ',', value_parser = parse_speed)]
+    pub speeds: Option<Vec<u32>>,
+}
+
+#[cfg(feature = "tui")]
+#[derive(Debug, Args)]
+pub struct HostArgs {
+    #[command(flatten)]
+    pub game: GameArgs,
+
+    #[command(flatten)]
+    pub connection: ConnectionArgs,
+
+    /// The address to listen on
+    #[arg(long, default_value = DEFAULT_ADDRESS)]
+    pub bind: String,
+
+    /// Your name, as shown to the other player [default: Host]
+    #[arg(long)]
+    pub name: Option<String>,
+
+    /// Stream the game's events as JSON lines to this file, or to a Unix socket as unix:PATH
+    #[arg(long = "events", value_parser = EventTarget::parse)]
+    pub events: Option<EventTarget>,
+}
+
+#[cfg(feature = "tui")]
+#[derive(Debug, Args)]
+pub struct ClientArgs {
+    /// The address of the host
+    #[arg(default_value = DEFAULT_ADDRESS)]
+    pub address: String,
+
+    #[command(flatten)]
+    pub connection: ConnectionArgs,
+
+    /// Milliseconds between frames [default: 120]
+    #[arg(long = "tick-ms", value_parser = parse_tick)]
+    pub tick: Option<Duration>,
+
+    /// Your name, as shown to the other player [default: Client]
+    #[arg(long)]
+    pub name: Option<String>,
+
+    /// Stream the game's events as JSON lines to this file, or to a Unix socket as unix:PATH
+    #[arg(long = "events", value_parser = EventTarget::parse)]
+    pub events: Option<EventTarget>,
+}
+
+// `headless <address>` is short for `headless join <address>`
+#[derive(Debug, Args)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct HeadlessArgs {
+    #[command(subcommand)]
+    pub role: Option<HeadlessRole>,
+
+    #[command(flatten)]
+    pub join: HeadlessJoinArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum HeadlessRole {
+    /// Join an online game (the default)
+    Join(HeadlessJoinArgs),
+    /// Wait for another player to join an online game
+    Host(HeadlessHostArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct HeadlessJoinArgs {
+    /// The address of the host
+    #[arg(default_value = DEFAULT_ADDRESS)]
+    pub address: String,
+
+    #[command(flatten)]
+    pub player: HeadlessPlayerArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct HeadlessHostArgs {
+    /// The address to listen on
+    #[arg(default_value = DEFAULT_ADDRESS)]
+    pub address: String,
+
+    /// Size of the arena, as WIDTHxHEIGHT [default: 35x16]
+    #[arg(long, value_parser = parse_size)]
+    pub size: Option<(u16, u16)>,
+
+    /// Who wins when the last players crash in the same frame: draw (nobody), most-kills or
+    /// longest-line [default: draw]
+    #[arg(long, value_parser = parse_tie_rule)]
+    pub tie: Option<TieRule>,
+
+    /// Place power-ups in the arena that speed up whoever takes them, or slow down everyone
+    /// else (--power-ups false turns them off)
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    pub power_ups: Option<bool>,
+
+    #[command(flatten)]
+    pub player: HeadlessPlayerArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct HeadlessPlayerArgs {
+    #[command(flatten)]
+    pub connection: ConnectionArgs,
+
+    /// Your name, as shown to the other player [default: Headless client or Headless host]
+    #[arg(long)]
+    pub name: Option<String>,
+
+    /// Play the moves in this file, one line per frame (see the README), instead of reading
+    /// them from stdin
+    #[arg(long, conflicts_with = "ai")]
+    pub script: Option<PathBuf>,
+
+    /// Let this AI strategy play, instead of reading moves from stdin
+    #[arg(long, value_parser = parse_strategy)]
+    pub ai: Option<AiKind>,
+
+    /// Seed for the random choices of the AI, so that it makes the same ones every game
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Milliseconds between frames [default: 120]
+    #[arg(long = "tick-ms", value_parser = parse_tick)]
+    pub tick: Option<Duration>,
+
+    /// Log as JSON lines instead of text
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[cfg(feature = "tui")]
+#[derive(Debug, Args)]
+pub struct ReplayArgs {
+    /// The event log
+    pub log: PathBuf,
+
+    /// Which round in the log to watch, counting from 1 [default: the last one]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub round: Option<u32>,
+
+    /// Milliseconds between frames [default: 120]
+    #[arg(long = "tick-ms", value_parser = parse_tick)]
+    pub tick: Option<Duration>,
+}
+
+#[derive(Debug, Args)]
+pub struct TournamentArgs {
+    /// Comma separated strategies, 2 to 8 of them, that all play in every game. The same one can
+    /// be given more than once.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = parse_strategy,
+        default_value = "greedy,random,flood-fill,wall-hugger,voronoi"
+    )]
+    pub strategies: Vec<AiKind>,
+
+    /// Number of games to play
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u32).range(1..))]
+    pub games: u32,
+
+    /// Size of the arena, as WIDTHxHEIGHT [default: 35x16]
+    #[arg(long, value_parser = parse_size)]
+    pub size: Option<(u16, u16)>,
+
+    /// Milliseconds that the minimax strategies may think per frame
+    #[arg(long = "think-ms", default_value_t = 5)]
+    pub think_ms: u64,
+
+    /// Number of games played at the same time [default: the number of CPU cores]
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    pub threads: Option<u16>,
+
+    /// Seed for the random choices of the strategies, so that a tournament can be run again.
+    /// The minimax strategies still depend on how fast the machine is.
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Print the results as JSON instead of a table
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct StatsArgs {
+    /// Only show these players, and the head-to-head records between them
+    pub names: Vec<String>,
+
+    /// Print the leaderboard as JSON instead of a table
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[cfg(feature = "tui")]
+#[derive(Debug, Default, Args)]
+pub struct GameArgs {
+    /// Size of the arena, as WIDTHxHEIGHT [default: 35x16]
+    #[arg(long, value_parser = parse_size)]
+    pub size: Option<(u16, u16)>,
+
+    /// Milliseconds between frames [default: 120]
+    #[arg(long = "tick-ms", value_parser = parse_tick)]
+    pub tick: Option<Duration>,
+
+    /// Who wins when the last players crash in the same frame: draw (nobody), most-kills or
+    /// longest-line [default: draw]
+    #[arg(long, value_parser = parse_tie_rule)]
+    pub tie: Option<TieRule>,
+
+    /// Place power-ups in the arena that speed up whoever takes them, or slow down everyone
+    /// else (--power-ups false turns them off)
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    pub power_ups: Option<bool>,
+}
+
+// Without a terminal UI, no command takes the flags that `config dump` would apply
+#[derive(Debug, Args)]
+pub struct DumpArgs {
+    #[cfg(feature = "tui")]
+    #[command(flatten)]
+    pub game: GameArgs,
+
+    #[cfg(feature = "tui")]
+    #[command(flatten)]
+    pub seats: SeatArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct ConnectionArgs {
+    /// Shared password that the client must know to join
+    #[arg(long, env = "ACHTUNG_PASSWORD", hide_env_values = true)]
+    pub password: Option<String>,
+
+    /// Pre-shared key that the game traffic is encrypted with
+    #[arg(long, env = "ACHTUNG_KEY", hide_env_values = true)]
+    pub key: Option<String>,
+}
+
+// Flags that are given on the command line win over the config file
+
+impl HeadlessHostArgs {
+    pub fn rules(&self, settings: &Settings) -> Rules {
+        Rules {
+            size: self.size.unwrap_or(settings.game_size),
+            tie_rule: self.tie.unwrap_or(settings.tie_rule),
+            power_ups: self.power_ups.unwrap_or(settings.power_ups),
+        }
+    }
+}
+
+#[cfg(feature = "tui")]
+impl GameArgs {
+    pub fn apply(&self, settings: &mut Settings) {
+        if let Some(size) = self.size {
+            settings.game_size = size;
+        }
+        if let Some(tick) = self.tick {
+            settings.tick_duration = tick;
+        }
+        if let Some(tie_rule) = self.tie {
+            settings.tie_rule = tie_rule;
+        }
+        if let Some(power_ups) = self.power_ups {
+            settings.power_ups = power_ups;
+        }
+    }
+}
+
+#[cfg(feature = "tui")]
+impl OfflineArgs {
+    pub fn apply(self, settings: &mut Settings) {
+        self.game.apply(settings);
+        self.seats.apply(settings);
+    }
+}
+
+#[cfg(feature = "tui")]
+impl SeatArgs {
+    pub fn apply(self, settings: &mut Settings) {
+        if let Some(seats) = self.seats {
+            settings.seats = seats;
+        }
+        // The count that isn't given is kept from the profile
+        if self.players.is_some() || self.ais.is_some() {
+            let count = |kind: fn(&Seat) -> bool| settings.seats.iter().filter(|s| kind(s)).count();
+            let players = self
+                .players
+                .map(usize::from)
+                .unwrap_or_else(|| count(|s| matches!(s, Seat::Keyboard { .. })));
+            let ais = self
+                .ais
+                .map(usize::from)
+                .unwrap_or_else(|| count(|s| matches!(s, Seat::Ai { .. })));
+            settings.seats = (0..players)
+                .map(|keys| Seat::Keyboard { keys })
+                .chain((0..ais).map(|_| Seat::Ai {
+                    strategy: AiKind::default(),
+                }))
+                .collect();
+        }
+        if let Some(names) = self.names {
+            settings.names = names;
+        }
+        if let Some(colors) = self.colors {
+            settings.colors = colors;
+        }
+        if let Some(speeds) = self.speeds {
+            settings.speeds = speeds;
+        }
+    }
+}
+
+impl DumpArgs {
+    #[cfg_attr(not(feature = "tui"), allow(unused_variables))]
+    pub fn apply(self, settings: &mut Settings) {
+        #[cfg(feature = "tui")]
+        {
+            self.game.apply(settings);
+            self.seats.apply(settings);
+        }
+    }
+}
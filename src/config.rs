@@ -0,0 +1,598 @@
+use crate::ai::AiKind;
+use crate::game::{PlayerColor, TieRule, MAX_SPEED};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const DEFAULT_PROFILE: &str = "default";
+pub const MAX_SEATS: usize = 8;
+
+/// The settings that a game is started with: the built-in defaults, overridden by the chosen
+/// profile in the config file, overridden by command line flags.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub profile: String,
+    pub game_size: (u16, u16),
+    pub tick_duration: Duration,
+    pub tie_rule: TieRule,
+    pub power_ups: bool,
+    pub seats: Vec<Seat>,
+    // Overrides for the first few players, in seat order (not counting empty seats)
+    pub names: Vec<String>,
+    pub colors: Vec<PlayerColor>,
+    // In percent of the normal speed, for handicaps in offline games
+    pub speeds: Vec<u32>,
+    // Picked by the keyboard seats. The first one is also used in online games.
+    pub key_bindings: Vec<KeyBinding>,
+    // Your name in online games
+    pub name: Option<String>,
+}
+
+impl Settings {
+    /// Loads the given profile from the config file. Without an explicit path, a missing file
+    /// in the user's config dir just means that the defaults are used.
+    pub fn load(path: Option<&Path>, profile: Option<&str>) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (Some(path.to_path_buf()), true),
+            None => (default_path(), false),
+        };
+
+        let file = match &path {
+            Some(path) if required || path.exists() => {
+                let text = fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read config file {:?}", path))?;
+                toml::from_str::<ConfigFile>(&text)
+                    .with_context(|| format!("Invalid config file {:?}", path))?
+            }
+            _ => ConfigFile::default(),
+        };
+
+        let source = match &path {
+            Some(path) => format!("{:?}", path),
+            None => "(no config dir found)".to_string(),
+        };
+        let name = profile
+            .map(str::to_string)
+            .or(file.profile)
+            .unwrap_or_else(|| DEFAULT_PROFILE.to_string());
+        let chosen = match file.profiles.get(&name) {
+            Some(chosen) => chosen.clone(),
+            None if name == DEFAULT_PROFILE => Profile::default(),
+            None => bail!(
+                "There is no profile '{}' in the config file {}",
+                name,
+                source
+            ),
+        };
+
+        chosen
+            .or(Profile::builtin())
+            .resolve(name.clone())
+            .with_context(|| format!("Invalid profile '{}' in the config file {}", name, source))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.validate_online()?;
+        if self.seats.len() > MAX_SEATS {
+            return Err(format!(
+                "There are at most {} seats (got {})",
+                MAX_SEATS,
+                self.seats.len()
+            ));
+        }
+        let total = self.seats.iter().filter(|s| **s != Seat::Empty).count();
+        if total < 2 {
+            return Err(format!(
+                "The game needs at least 2 players (got {} occupied seats)",
+                total
+            ));
+        }
+        let mut used_keys = vec![];
+        for seat in &self.seats {
+            if let Seat::Keyboard { keys } = *seat {
+                if keys >= self.key_bindings.len() {
+                    return Err(format!(
+                        "There is no key set {} (there are {})",
+                        keys + 1,
+                        self.key_bindings.len()
+                    ));
+                }
+                if used_keys.contains(&keys) {
+                    return Err(format!("Key set {} is used by two seats", keys + 1));
+                }
+                used_keys.push(keys);
+            }
+        }
+        if self.names.len() > total {
+            return Err(format!(
+                "Got {} names for {} players",
+                self.names.len(),
+                total
+            ));
+        }
+        if self.colors.len() > total {
+            return Err(format!(
+                "Got {} colors for {} players",
+                self.colors.len(),
+                total
+            ));
+        }
+        if self.speeds.len() > total {
+            return Err(format!(
+                "Got {} speeds for {} players",
+                self.speeds.len(),
+                total
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks what an online game uses of the settings: the arena and tick, which the host sends
+    /// to the client, and the first key set, which the local player steers with
+    pub fn validate_online(&self) -> Result<(), String> {
+        check_size(self.game_size)?;
+        check_tick(self.tick_duration)?;
+        if self.key_bindings.is_empty() {
+            return Err("There are no key sets to play with".to_string());
+        }
+        Ok(())
+    }
+
+    /// The settings in the config file format, as a profile that is selected by default
+    pub fn dump(&self) -> String {
+        let profile = Profile {
+            size: Some(format!("{}x{}", self.game_size.0, self.game_size.1)),
+            tick_ms: Some(self.tick_duration.as_millis() as u64),
+            tie: Some(self.tie_rule.name().to_string()),
+            power_ups: Some(self.power_ups),
+            seats: Some(self.seats.iter().map(|seat| seat.to_string()).collect()),
+            names: Some(self.names.clone()),
+            colors: Some(self.colors.iter().map(|c| color_name(*c)).collect()),
+            speeds: Some(self.speeds.clone()),
+            name: self.name.clone(),
+            keys: Some(
+                self.key_bindings
+                    .iter()
+                    .map(|binding| match *binding {
+                        KeyBinding::Absolute { keys, boost } => KeyBindings::Absolute {
+                            up: key_name(keys[0]),
+                            left: key_name(keys[1]),
+                            down: key_name(keys[2]),
+                            right: key_name(keys[3]),
+                            boost: boost.map(key_name),
+                        },
+                        KeyBinding::Relative {
+                            turn_left,
+                            turn_right,
+                            boost,
+                        } => KeyBindings::Relative {
+                            turn_left: key_name(turn_left),
+                            turn_right: key_name(turn_right),
+                            boost: boost.map(key_name),
+                        },
+                    })
+                    .collect(),
+            ),
+        };
+        let file = ConfigFile {
+            profile: Some(self.profile.clone()),
+            profiles: BTreeMap::from([(self.profile.clone(), profile)]),
+        };
+        toml::to_string(&file).expect("Settings can always be serialized")
+    }
+}
+
+/// Who, if anyone, plays from a seat in an offline game
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Seat {
+    /// Steered from the keyboard, with the key set at this index
+    Keyboard {
+        keys: usize,
+    },
+    Ai {
+        strategy: AiKind,
+    },
+    /// Played by a child process, see `ExternalBot`
+    Bot {
+        command: String,
+    },
+    Empty,
+}
+
+impl fmt::Display for Seat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Seat::Keyboard { keys } => write!(f, "keys:{}", keys + 1),
+            Seat::Ai { strategy } if *strategy == AiKind::default() => write!(f, "ai"),
+            Seat::Ai { strategy } => write!(f, "ai:{}", strategy),
+            Seat::Bot { command } => write!(f, "bot:{}", command),
+            Seat::Empty => write!(f, "empty"),
+        }
+    }
+}
+
+/// `keys:N` (N counts from 1), `ai`, `ai:STRATEGY`, `bot:COMMAND` or `empty`
+pub fn parse_seat(s: &str) -> Result<Seat, String> {
+    match s.split_once(':') {
+        Some(("keys", n)) => match n.parse::<usize>() {
+            Ok(n) if n >= 1 => Ok(Seat::Keyboard { keys: n - 1 }),
+            _ => Err(format!("Bad key set number in '{}'", s)),
+        },
+        Some(("ai", name)) => Ok(Seat::Ai {
+            strategy: parse_strategy(name)?,
+        }),
+        Some(("bot", command)) if !command.trim().is_empty() => Ok(Seat::Bot {
+            command: command.trim().to_string(),
+        }),
+        None if s == "ai" => Ok(Seat::Ai {
+            strategy: AiKind::default(),
+        }),
+        None if s == "empty" => Ok(Seat::Empty),
+        _ => Err(format!(
+            "Unknown seat '{}' (expected keys:N, ai, ai:STRATEGY, bot:COMMAND or empty)",
+            s
+        )),
+    }
+}
+
+pub fn parse_strategy(name: &str) -> Result<AiKind, String> {
+    AiKind::parse(name).ok_or_else(|| {
+        let names: Vec<&str> = AiKind::ALL.iter().map(AiKind::name).collect();
+        format!(
+            "Unknown AI strategy '{}' (expected one of {})",
+            name,
+            names.join(", ")
+        )
+    })
+}
+
+/// How a keyboard player steers, and the key it boosts with, if any
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyBinding {
+    /// One key per direction: up, left, down, right
+    Absolute { keys: [Key; 4], boost: Option<Key> },
+    /// Two keys that turn 90 degrees relative to the current heading, like in the original game
+    Relative {
+        turn_left: Key,
+        turn_right: Key,
+        boost: Option<Key>,
+    },
+}
+
+impl KeyBinding {
+    pub fn boost(&self) -> Option<Key> {
+        match *self {
+            KeyBinding::Absolute { boost, .. } | KeyBinding::Relative { boost, .. } => boost,
+        }
+    }
+}
+
+/// A key that can be bound to steering, independent of the terminal library that reads it
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Key {
+    Char(char),
+    Up,
+    Left,
+    Down,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+}
+
+/// `<config dir>/achtung/config.toml`, e.g. `~/.config/achtung/config.toml` on Linux
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("achtung").join("config.toml"))
+}
+
+// profile = "big"
+//
+// [profiles.big]
+// size = "80x30"
+// seats = ["keys:1", "ai", "ai", "ai"]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    // The profile to use when none is given on the command line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<String>,
+    profiles: BTreeMap<String, Profile>,
+}
+
+// Anything left out falls back to the built-in default
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Profile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tick_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tie: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    power_ups: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seats: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    names: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    colors: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    speeds: Option<Vec<u32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keys: Option<Vec<KeyBindings>>,
+}
+
+// Either `{ up, left, down, right }` or `{ turn_left, turn_right }`, with an optional `boost`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+enum KeyBindings {
+    Absolute {
+        up: String,
+        left: String,
+        down: String,
+        right: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        boost: Option<String>,
+    },
+    Relative {
+        turn_left: String,
+        turn_right: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        boost: Option<String>,
+    },
+}
+
+impl Profile {
+    fn builtin() -> Self {
+        let keys =
+            |up: &str, left: &str, down: &str, right: &str, boost: &str| KeyBindings::Absolute {
+                up: up.to_string(),
+                left: left.to_string(),
+                down: down.to_string(),
+                right: right.to_string(),
+                boost: Some(boost.to_string()),
+            };
+        Self {
+            size: Some("35x16".to_string()),
+            tick_ms: Some(120),
+            tie: Some(TieRule::default().name().to_string()),
+            power_ups: Some(false),
+            seats: Some(vec![
+                "keys:1".to_string(),
+                "keys:2".to_string(),
+                "ai".to_string(),
+                "ai".to_string(),
+            ]),
+            names: Some(vec![]),
+            colors: Some(vec![]),
+            speeds: Some(vec![]),
+            name: None,
+            keys: Some(vec![
+                keys("w", "a", "s", "d", "e"),
+                keys("Up", "Left", "Down", "Right", "End"),
+            ]),
+        }
+    }
+
+    fn or(self, fallback: Profile) -> Profile {
+        Profile {
+            size: self.size.or(fallback.size),
+            tick_ms: self.tick_ms.or(fallback.tick_ms),
+            tie: self.tie.or(fallback.tie),
+            power_ups: self.power_ups.or(fallback.power_ups),
+            seats: self.seats.or(fallback.seats),
+            names: self.names.or(fallback.names),
+            colors: self.colors.or(fallback.colors),
+            speeds: self.speeds.or(fallback.speeds),
+            name: self.name.or(fallback.name),
+            keys: self.keys.or(fallback.keys),
+        }
+    }
+
+    // Expects a profile that has been merged with the built-in one
+    fn resolve(self, profile: String) -> Result<Settings> {
+        let colors = self.colors.unwrap_or_default();
+        let keys = self.keys.unwrap_or_default();
+        if keys.is_empty() {
+            bail!("At least one set of keys is needed");
+        }
+        Ok(Settings {
+            profile,
+            game_size: parse_size(&self.size.unwrap_or_default()).map_err(|e| anyhow!(e))?,
+            tick_duration: parse_tick(&self.tick_ms.unwrap_or_default().to_string())
+                .map_err(|e| anyhow!(e))?,
+            tie_rule: parse_tie_rule(&self.tie.unwrap_or_default()).map_err(|e| anyhow!(e))?,
+            power_ups: self.power_ups.unwrap_or_default(),
+            seats: self
+                .seats
+                .unwrap_or_default()
+                .iter()
+                .map(|seat| parse_seat(seat))
+                .collect::<Result<_, _>>()
+                .map_err(|e| anyhow!(e))?,
+            names: self.names.unwrap_or_default(),
+            colors: colors
+                .iter()
+                .map(|c| parse_color(c))
+                .collect::<Result<_, _>>()
+                .map_err(|e| anyhow!(e))?,
+            speeds: self
+                .speeds
+                .unwrap_or_default()
+                .iter()
+                .map(|speed| parse_speed(&speed.to_string()))
+                .collect::<Result<_, _>>()
+                .map_err(|e| anyhow!(e))?,
+            key_bindings: keys
+                .iter()
+                .map(|k| match k {
+                    KeyBindings::Absolute {
+                        up,
+                        left,
+                        down,
+                        right,
+                        boost,
+                    } => Ok(KeyBinding::Absolute {
+                        keys: [
+                            parse_key(up)?,
+                            parse_key(left)?,
+                            parse_key(down)?,
+                            parse_key(right)?,
+                        ],
+                        boost: boost.as_deref().map(parse_key).transpose()?,
+                    }),
+                    KeyBindings::Relative {
+                        turn_left,
+                        turn_right,
+                        boost,
+                    } => Ok(KeyBinding::Relative {
+                        turn_left: parse_key(turn_left)?,
+                        turn_right: parse_key(turn_right)?,
+                        boost: boost.as_deref().map(parse_key).transpose()?,
+                    }),
+                })
+                .collect::<Result<_, String>>()
+                .map_err(|e| anyhow!(e))?,
+            name: self.name,
+        })
+    }
+}
+
+pub fn parse_size(s: &str) -> Result<(u16, u16), String> {
+    let (w, h) = s
+        .split_once('x')
+        .ok_or_else(|| format!("Expected WIDTHxHEIGHT, got '{}'", s))?;
+    let w: u16 = w.parse().map_err(|e| format!("Bad width '{}': {}", w, e))?;
+    let h: u16 = h
+        .parse()
+        .map_err(|e| format!("Bad height '{}': {}", h, e))?;
+    check_size((w, h))?;
+    Ok((w, h))
+}
+
+fn check_size((w, h): (u16, u16)) -> Result<(), String> {
+    if !(8..=250).contains(&w) || !(6..=100).contains(&h) {
+        return Err(format!(
+            "{}x{} is out of range (8x6 to 250x100 is supported)",
+            w, h
+        ));
+    }
+    Ok(())
+}
+
+pub fn parse_tick(s: &str) -> Result<Duration, String> {
+    let millis: u64 = s.parse().map_err(|e| format!("{}", e))?;
+    let tick = Duration::from_millis(millis);
+    check_tick(tick)?;
+    Ok(tick)
+}
+
+fn check_tick(tick: Duration) -> Result<(), String> {
+    let millis = tick.as_millis();
+    if !(20..=2000).contains(&millis) {
+        return Err(format!("{}ms is out of range (20 to 2000)", millis));
+    }
+    Ok(())
+}
+
+/// In percent of the normal speed, e.g. 50 for half of it
+pub fn parse_speed(s: &str) -> Result<u32, String> {
+    let speed: u32 = s.parse().map_err(|e| format!("{}", e))?;
+    if !(10..=MAX_SPEED).contains(&speed) {
+        return Err(format!("{}% is out of range (10 to {})", speed, MAX_SPEED));
+    }
+    Ok(speed)
+}
+
+pub fn parse_tie_rule(s: &str) -> Result<TieRule, String> {
+    TieRule::parse(s).ok_or_else(|| {
+        let names: Vec<&str> = TieRule::ALL.iter().map(TieRule::name).collect();
+        format!(
+            "Unknown tie rule '{}' (expected one of {})",
+            s,
+            names.join(", ")
+        )
+    })
+}
+
+const COLORS: [(&str, PlayerColor); 14] = [
+    ("red", PlayerColor::Red),
+    ("green", PlayerColor::Green),
+    ("yellow", PlayerColor::Yellow),
+    ("blue", PlayerColor::Blue),
+    ("magenta", PlayerColor::Magenta),
+    ("cyan", PlayerColor::Cyan),
+    ("white", PlayerColor::White),
+    ("gray", PlayerColor::Gray),
+    ("lightred", PlayerColor::LightRed),
+    ("lightgreen", PlayerColor::LightGreen),
+    ("lightyellow", PlayerColor::LightYellow),
+    ("lightblue", PlayerColor::LightBlue),
+    ("lightmagenta", PlayerColor::LightMagenta),
+    ("lightcyan", PlayerColor::LightCyan),
+];
+
+pub fn parse_color(s: &str) -> Result<PlayerColor, String> {
+    let lowercase = s.to_ascii_lowercase().replace("grey", "gray");
+    COLORS
+        .iter()
+        .find(|(name, _)| *name == lowercase)
+        .map(|(_, color)| *color)
+        .ok_or_else(|| format!("Unknown color '{}'", s))
+}
+
+pub fn color_name(color: PlayerColor) -> String {
+    COLORS
+        .iter()
+        .find(|(_, c)| *c == color)
+        .map(|(name, _)| name.to_string())
+        .unwrap_or_else(|| format!("{:?}", color))
+}
+
+const NAMED_KEYS: [(&str, Key); 9] = [
+    ("Up", Key::Up),
+    ("Left", Key::Left),
+    ("Down", Key::Down),
+    ("Right", Key::Right),
+    ("Home", Key::Home),
+    ("End", Key::End),
+    ("PageUp", Key::PageUp),
+    ("PageDown", Key::PageDown),
+    ("Insert", Key::Insert),
+];
+
+// A single character, or one of the NAMED_KEYS. `q`, Enter, Esc and F3 are taken by the app.
+fn parse_key(s: &str) -> Result<Key, String> {
+    let mut chars = s.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        if c == 'q' {
+            return Err("'q' is reserved for quitting".to_string());
+        }
+        return Ok(Key::Char(c));
+    }
+    NAMED_KEYS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(s))
+        .map(|(_, key)| *key)
+        .ok_or_else(|| format!("Unknown key '{}'", s))
+}
+
+fn key_name(key: Key) -> String {
+    match key {
+        Key::Char(c) => c.to_string(),
+        _ => NAMED_KEYS
+            .iter()
+            .find(|(_, k)| *k == key)
+            .map(|(name, _)| name.to_string())
+            .unwrap_or_else(|| format!("{:?}", key)),
+    }
+}
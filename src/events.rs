@@ -0,0 +1,180 @@
+use crate::config;
+use crate::game::{direction_name, Direction, FrameEvent, Game, PlayerIndex};
+use serde_json::{json, Value};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+/// Where an `EventLog` is written to
+#[derive(Debug, Clone)]
+pub enum EventTarget {
+    /// A file, which is appended to
+    File(PathBuf),
+    /// A Unix socket that something is already listening on
+    UnixSocket(PathBuf),
+}
+
+impl EventTarget {
+    /// `unix:PATH` for a Unix socket, anything else is a file
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("Expected a path after 'unix:'".to_string()),
+            Some(path) => Ok(EventTarget::UnixSocket(path.into())),
+            None if s.is_empty() => Err("Expected a file or unix:PATH".to_string()),
+            None => Ok(EventTarget::File(s.into())),
+        }
+    }
+}
+
+impl fmt::Display for EventTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventTarget::File(path) => write!(f, "{}", path.display()),
+            EventTarget::UnixSocket(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Writes what happens in a game as JSON lines, for tools like stats dashboards and chat bots.
+/// Every line has a `type` and the `frame` it happened in:
+///
+/// - `round_start`: the round, the mode, the arena size, the rules, and the players' names,
+///   colors, start positions and speeds
+/// - `direction`: a player turned, as of this frame
+/// - `boost`: a player boosted, as of this frame
+/// - `crashed`: the player that did, the `cause` (`wall`, `own_trail`, `trail` or `head_on`) and,
+///   for the last two, the player it was `by`
+/// - `won`: the player that did
+/// - `everyone_crashed`
+/// - `power_up`: a player took a power-up, `speed_up` or `slow_down`
+/// - `round_end`: the winner, if there is one, and everyone's score and kills
+/// - `remote_left`: the other player of an online game left
+pub struct EventLog {
+    writer: Box<dyn Write + Send>,
+    // What each player was heading for in the last frame that was logged
+    directions: Vec<Direction>,
+    winner: Option<PlayerIndex>,
+}
+
+impl EventLog {
+    pub fn open(target: &EventTarget) -> io::Result<Self> {
+        let writer: Box<dyn Write + Send> = match target {
+            EventTarget::File(path) => {
+                Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+            }
+            #[cfg(unix)]
+            EventTarget::UnixSocket(path) => {
+                Box::new(std::os::unix::net::UnixStream::connect(path)?)
+            }
+            #[cfg(not(unix))]
+            EventTarget::UnixSocket(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Unix sockets aren't supported on this platform",
+                ))
+            }
+        };
+        Ok(Self {
+            writer: Box::new(BufWriter::new(writer)),
+            directions: vec![],
+            winner: None,
+        })
+    }
+
+    /// `mode` is `offline`, `host` or `client`
+    pub fn round_start(&mut self, game: &Game, round: u32, mode: &str) -> io::Result<()> {
+        self.directions = game.players.iter().map(|p| p.direction).collect();
+        self.winner = None;
+        let players: Vec<Value> = game
+            .players
+            .iter()
+            .map(|p| {
+                json!({
+                    "name": p.name,
+                    "color": config::color_name(p.color),
+                    "start": p.head(),
+                    "direction": direction_name(p.direction),
+                    "speed": p.base_speed,
+                })
+            })
+            .collect();
+        self.write(&[json!({
+            "type": "round_start",
+            "frame": game.frame,
+            "round": round,
+            "mode": mode,
+            "size": game.size(),
+            "tie": game.tie_rule.name(),
+            "power_ups": game.spawn_power_ups,
+            "players": players,
+        })])
+    }
+
+    /// Logs a frame that has just been run: who turned in it, and what it led to
+    pub fn frame(&mut self, game: &Game, events: &[FrameEvent]) -> io::Result<()> {
+        // The game has already moved on to the next frame
+        let frame = game.frame - 1;
+        let mut lines = vec![];
+        for (i, player) in game.players.iter().enumerate() {
+            if player.direction != self.directions[i] {
+                self.directions[i] = player.direction;
+                lines.push(json!({
+                    "type": "direction",
+                    "frame": frame,
+                    "player": i,
+                    "direction": direction_name(player.direction),
+                }));
+            }
+        }
+        for event in events {
+            if let FrameEvent::PlayerWon(i) = event {
+                self.winner = Some(*i);
+            }
+            lines.push(event_line(frame, event));
+        }
+        if game.game_over {
+            lines.push(json!({
+                "type": "round_end",
+                "frame": frame,
+                "winner": self.winner,
+                "scores": game.players.iter().map(|p| p.score).collect::<Vec<_>>(),
+                "kills": game.players.iter().map(|p| p.kills).collect::<Vec<_>>(),
+            }));
+        }
+        self.write(&lines)
+    }
+
+    pub fn remote_left(&mut self, game: &Game) -> io::Result<()> {
+        self.write(&[json!({ "type": "remote_left", "frame": game.frame })])
+    }
+
+    fn write(&mut self, lines: &[Value]) -> io::Result<()> {
+        for line in lines {
+            writeln!(self.writer, "{}", line)?;
+        }
+        self.writer.flush()
+    }
+}
+
+// Also what a replay checks the frames it runs against
+pub(crate) fn event_line(frame: u32, event: &FrameEvent) -> Value {
+    match event {
+        FrameEvent::Boosted(i) => json!({ "type": "boost", "frame": frame, "player": i }),
+        FrameEvent::PlayerCrashed(i, cause) => json!({
+            "type": "crashed",
+            "frame": frame,
+            "player": i,
+            "cause": cause.name(),
+            "by": cause.other_player(),
+        }),
+        FrameEvent::PlayerWon(i) => json!({ "type": "won", "frame": frame, "player": i }),
+        FrameEvent::EveryoneCrashed => json!({ "type": "everyone_crashed", "frame": frame }),
+        FrameEvent::PowerUpTaken(i, power_up) => json!({
+            "type": "power_up",
+            "frame": frame,
+            "player": i,
+            "power_up": power_up.name(),
+        }),
+    }
+}
@@ -1,5 +1,4 @@
 use crate::Point;
-use tui::style::Color;
 
 pub type PlayerIndex = usize;
 pub type Direction = (i32, i32);
@@ -11,11 +10,85 @@ pub const RIGHT: Direction = (1, 0);
 
 pub const DIRECTIONS: [Direction; 4] = [UP, LEFT, DOWN, RIGHT];
 
+/// Speeds are in percent of a cell per frame. What a player has travelled is added up from frame
+/// to frame, so that e.g. a player at 150 moves 1 and 2 cells every other frame.
+pub const NORMAL_SPEED: u32 = 100;
+/// No one moves more than 2 cells in a frame
+pub const MAX_SPEED: u32 = 200;
+const MAX_CELLS_PER_FRAME: u32 = 2;
+
+const BOOST_FRAMES: u32 = 8;
+/// Frames from the start of a boost until the next one can be started
+pub const BOOST_COOLDOWN_FRAMES: u32 = 60;
+
+// A power-up is placed every this many frames, as long as there are fewer than the max
+const POWER_UP_INTERVAL: u32 = 25;
+const MAX_POWER_UPS: usize = 3;
+const POWER_UP_FRAMES: u32 = 40;
+
+#[derive(Debug, Copy, Clone)]
+enum Side {
+    North,
+    West,
+    South,
+    East,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct StartPosition {
+    side: Side,
+    // How far along the side the player starts, as the fraction `slot / slots`
+    slot: u16,
+    slots: u16,
+}
+
+impl StartPosition {
+    /// Spreads the players over the sides of the arena, all facing inwards: the first four get
+    /// the middle of the west, east, north and south side, and any more share the sides evenly.
+    pub fn spread(count: usize) -> Vec<Self> {
+        let sides = [Side::West, Side::East, Side::North, Side::South];
+        (0..count)
+            .map(|i| {
+                let players_on_side = (count - i % 4).div_ceil(4);
+                StartPosition {
+                    side: sides[i % 4],
+                    slot: (i / 4 + 1) as u16,
+                    slots: (players_on_side + 1) as u16,
+                }
+            })
+            .collect()
+    }
+
+    pub fn resolve(&self, size: (u16, u16)) -> (Point, Direction) {
+        let along = |len: u16| (len as u32 * self.slot as u32 / self.slots as u32) as i32;
+        let (w, h) = (size.0 as i32, size.1 as i32);
+        match self.side {
+            Side::North => ((along(size.0), 0), DOWN),
+            Side::West => ((0, along(size.1)), RIGHT),
+            Side::South => ((along(size.0), h - 1), UP),
+            Side::East => ((w - 1, along(size.1)), LEFT),
+        }
+    }
+
+    pub fn direction(&self) -> Direction {
+        match self.side {
+            Side::North => DOWN,
+            Side::West => RIGHT,
+            Side::South => UP,
+            Side::East => LEFT,
+        }
+    }
+}
+
 pub struct Game {
     size: (u16, u16),
     pub game_over: bool,
     pub players: Vec<Player>,
     pub frame: u32,
+    pub tie_rule: TieRule,
+    /// Whether power-ups show up in the arena
+    pub spawn_power_ups: bool,
+    pub power_ups: Vec<(Point, PowerUp)>,
 }
 
 impl Game {
@@ -25,32 +98,105 @@ impl Game {
             game_over: false,
             players,
             frame,
+            tie_rule: TieRule::default(),
+            spawn_power_ups: false,
+            power_ups: vec![],
         }
     }
 
+    pub fn size(&self) -> (u16, u16) {
+        self.size
+    }
+
+    /// Moves everyone at once, in steps of one cell: everyone that has a cell left to move in the
+    /// frame moves it in the same step, so a player at double speed moves its second cell while
+    /// the others stand still. Who crashes doesn't depend on the order of the players: all of the
+    /// crashes of a step are found before anyone is marked as crashed, see `crash_cause`.
     pub fn run_frame(&mut self) -> Vec<FrameEvent> {
-        let mut events = vec![];
+        let mut events: Vec<FrameEvent> = self
+            .players
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, p)| std::mem::take(&mut p.boosted).then_some(FrameEvent::Boosted(i)))
+            .collect();
+        let cells: Vec<u32> = self
+            .players
+            .iter_mut()
+            .map(|p| if p.crashed { 0 } else { p.travel() })
+            .collect();
         for player in &mut self.players {
-            if !player.crashed {
+            player.moved = 0;
+        }
+
+        // Whoever crashed in the latest step that someone crashed in
+        let mut last: Vec<PlayerIndex> = vec![];
+        for step in 0..MAX_CELLS_PER_FRAME {
+            let moving: Vec<bool> = (0..self.players.len())
+                .map(|i| cells[i] > step && !self.players[i].crashed)
+                .collect();
+            for (player, _) in self.players.iter_mut().zip(&moving).filter(|(_, m)| **m) {
                 player.advance_one_step();
             }
-        }
 
-        for i in 0..self.players.len() {
-            if !self.players[i].crashed && self.is_player_crashing(i) {
+            let crashes: Vec<(PlayerIndex, CrashCause)> = (0..self.players.len())
+                .filter(|i| moving[*i])
+                .filter_map(|i| self.crash_cause(i, &moving).map(|cause| (i, cause)))
+                .collect();
+            for &(i, cause) in &crashes {
                 self.players[i].crashed = true;
-                events.push(FrameEvent::PlayerCrashed(i));
+                if let CrashCause::Trail(owner) = cause {
+                    self.players[owner].kills += 1;
+                }
+                events.push(FrameEvent::PlayerCrashed(i, cause));
+            }
+
+            let mut taken: Vec<(PlayerIndex, PowerUp)> = vec![];
+            for i in (0..self.players.len()).filter(|i| moving[*i]) {
+                if !self.players[i].crashed {
+                    if let Some(power_up) = self.take_power_up(i) {
+                        taken.push((i, power_up));
+                    }
+                }
+            }
+            // A player that takes a speed-up keeps it, even if someone else takes a slow-down in
+            // the same step
+            for power_up in [PowerUp::SlowDown, PowerUp::SpeedUp] {
+                for &(i, _) in taken.iter().filter(|(_, p)| *p == power_up) {
+                    self.use_power_up(i, power_up);
+                }
+            }
+            events.extend(
+                taken
+                    .into_iter()
+                    .map(|(i, power_up)| FrameEvent::PowerUpTaken(i, power_up)),
+            );
+            if !crashes.is_empty() {
+                last = crashes.iter().map(|(i, _)| *i).collect();
+            }
+            // Once the round is decided, nobody moves any further
+            if self.players.iter().filter(|p| !p.crashed).count() <= 1 {
+                break;
             }
         }
 
-        let mut survivors = self.players.iter().filter(|p| !p.crashed);
-        if let Some(survivor) = survivors.next() {
+        for player in &mut self.players {
+            player.wear_off();
+        }
+        if self.spawn_power_ups {
+            self.place_power_up();
+        }
+
+        let mut survivors = (0..self.players.len()).filter(|i| !self.players[*i].crashed);
+        if let Some(survivor_i) = survivors.next() {
             if survivors.next().is_none() {
-                events.push(FrameEvent::PlayerWon(survivor.color, survivor.name.clone()));
+                events.push(FrameEvent::PlayerWon(survivor_i));
                 self.game_over = true;
             }
         } else {
-            events.push(FrameEvent::EveryoneCrashed);
+            match self.break_tie(&last) {
+                Some(winner_i) => events.push(FrameEvent::PlayerWon(winner_i)),
+                None => events.push(FrameEvent::EveryoneCrashed),
+            }
             self.game_over = true;
         }
 
@@ -75,59 +221,374 @@ impl Game {
         true
     }
 
-    fn is_player_crashing(&self, player_index: PlayerIndex) -> bool {
+    /// A line like "Alice cut off Bob!", for a crash that happened in the game
+    pub fn crash_message(&self, player_index: PlayerIndex, cause: CrashCause) -> String {
+        let name = &self.players[player_index].name;
+        match cause {
+            CrashCause::Wall => format!("{} hit the wall!", name),
+            CrashCause::OwnTrail => format!("{} ran into their own line!", name),
+            CrashCause::Trail(owner) => format!("{} cut off {}!", self.players[owner].name, name),
+            CrashCause::HeadOn(other) => {
+                format!("{} and {} crashed head-on!", name, self.players[other].name)
+            }
+        }
+    }
+
+    // Removes the power-up that the player has moved onto, if there is one
+    fn take_power_up(&mut self, player_index: PlayerIndex) -> Option<PowerUp> {
         let head = self.players[player_index].head();
+        let i = self
+            .power_ups
+            .iter()
+            .position(|(point, _)| *point == head)?;
+        Some(self.power_ups.remove(i).1)
+    }
+
+    fn use_power_up(&mut self, player_index: PlayerIndex, power_up: PowerUp) {
+        match power_up {
+            PowerUp::SpeedUp => self.players[player_index].affect(SpeedEffect::SpeedUp),
+            PowerUp::SlowDown => {
+                for (i, player) in self.players.iter_mut().enumerate() {
+                    if i != player_index && !player.crashed {
+                        player.affect(SpeedEffect::SlowDown);
+                    }
+                }
+            }
+        }
+    }
+
+    // The cell is picked from the frame number, so that online peers place the same power-ups
+    // without having to agree on them
+    fn place_power_up(&mut self) {
+        if !self.frame.is_multiple_of(POWER_UP_INTERVAL) || self.power_ups.len() >= MAX_POWER_UPS {
+            return;
+        }
+        let cells = self.size.0 as u64 * self.size.1 as u64;
+        let mut hash = scramble(self.frame as u64);
+        // Gives up on a crowded arena rather than looking through all of it
+        for _ in 0..16 {
+            let cell = hash % cells;
+            let point = (
+                (cell % self.size.0 as u64) as i32,
+                (cell / self.size.0 as u64) as i32,
+            );
+            let taken = self.power_ups.iter().any(|(p, _)| *p == point);
+            if self.is_vacant(point) && !taken {
+                let power_up = if (hash >> 32).is_multiple_of(2) {
+                    PowerUp::SpeedUp
+                } else {
+                    PowerUp::SlowDown
+                };
+                self.power_ups.push((point, power_up));
+                return;
+            }
+            hash = scramble(hash);
+        }
+    }
+
+    // Called after the players in `moving` have moved a cell, and before anyone is marked as
+    // crashed. A cell that was taken before the step is what the player crashed into, even if
+    // someone else moved into it too. Two players that move into the same free cell, or into
+    // each other's cells, crash head-on.
+    fn crash_cause(&self, player_index: PlayerIndex, moving: &[bool]) -> Option<CrashCause> {
+        let player = &self.players[player_index];
+        let head = player.head();
         if !self.is_within_game_bounds(head) {
-            return true;
+            return Some(CrashCause::Wall);
+        }
+        // A player can not be crashing with its own head
+        if player.tail().contains(&head) {
+            return Some(CrashCause::OwnTrail);
         }
 
-        for (i, player) in self.players.iter().enumerate() {
-            let obstacle = if i == player_index {
-                // A player can not be crashing with its own head
-                player.tail()
+        let moved = |(i, _): &(PlayerIndex, &Player)| *i != player_index && moving[*i];
+        let others = || self.players.iter().enumerate();
+        if let Some((i, _)) = others().filter(moved).find(|(_, other)| {
+            other.previous_head() == head && other.head() == player.previous_head()
+        }) {
+            return Some(CrashCause::HeadOn(i));
+        }
+        for (i, other) in others().filter(|(i, _)| *i != player_index) {
+            let before_step = if moving[i] {
+                other.tail()
             } else {
-                player.full_body()
+                other.full_body()
             };
-            if obstacle.contains(&head) {
-                return true;
+            if before_step.contains(&head) {
+                return Some(CrashCause::Trail(i));
             }
         }
-        false
+        others()
+            .filter(moved)
+            .find(|(_, other)| other.head() == head)
+            .map(|(i, _)| CrashCause::HeadOn(i))
+    }
+
+    // Picks a winner among the last players, who all crashed in the same frame
+    fn break_tie(&self, last: &[PlayerIndex]) -> Option<PlayerIndex> {
+        let measure = |i: &PlayerIndex| match self.tie_rule {
+            TieRule::Draw => 0,
+            TieRule::MostKills => self.players[*i].kills as usize,
+            TieRule::LongestLine => self.players[*i].line.len(),
+        };
+        let best = last.iter().map(measure).max()?;
+        match last
+            .iter()
+            .filter(|i| measure(i) == best)
+            .collect::<Vec<_>>()[..]
+        {
+            [winner_i] if self.tie_rule != TieRule::Draw => Some(*winner_i),
+            _ => None,
+        }
+    }
+}
+
+/// Who wins a round when the last players all crash in the same frame
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum TieRule {
+    /// Nobody does
+    #[default]
+    Draw,
+    /// The one of them with the most kills in the round, if there is just one
+    MostKills,
+    /// The one of them with the longest line, if there is just one
+    LongestLine,
+}
+
+impl TieRule {
+    pub const ALL: [TieRule; 3] = [TieRule::Draw, TieRule::MostKills, TieRule::LongestLine];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TieRule::Draw => "draw",
+            TieRule::MostKills => "most-kills",
+            TieRule::LongestLine => "longest-line",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|rule| rule.name() == name)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum FrameEvent {
-    PlayerCrashed(PlayerIndex),
-    PlayerWon(Color, String),
+    /// A boost that the player started since the last frame, which goes into effect in this one
+    Boosted(PlayerIndex),
+    PlayerCrashed(PlayerIndex, CrashCause),
+    PlayerWon(PlayerIndex),
     EveryoneCrashed,
+    PowerUpTaken(PlayerIndex, PowerUp),
+}
+
+/// Something in the arena that changes the speed of whoever moves over it, or of everyone else
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PowerUp {
+    /// The player that takes it gets faster for a while
+    SpeedUp,
+    /// Everyone else gets slower for a while
+    SlowDown,
+}
+
+impl PowerUp {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PowerUp::SpeedUp => "speed_up",
+            PowerUp::SlowDown => "slow_down",
+        }
+    }
+}
+
+/// A change to a player's speed that wears off. A player has at most one, the latest.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SpeedEffect {
+    Boost,
+    SpeedUp,
+    SlowDown,
+}
+
+impl SpeedEffect {
+    // In percent of the player's own speed
+    fn factor(&self) -> u32 {
+        match self {
+            SpeedEffect::Boost => 200,
+            SpeedEffect::SpeedUp => 150,
+            SpeedEffect::SlowDown => 50,
+        }
+    }
+
+    fn frames(&self) -> u32 {
+        match self {
+            SpeedEffect::Boost => BOOST_FRAMES,
+            SpeedEffect::SpeedUp | SpeedEffect::SlowDown => POWER_UP_FRAMES,
+        }
+    }
+}
+
+/// What a player crashed into
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CrashCause {
+    Wall,
+    OwnTrail,
+    /// The line of another player, who is credited with the kill. That also goes for the head
+    /// of a player that had already crashed.
+    Trail(PlayerIndex),
+    /// Another player moved into the same cell in the same frame. Nobody gets the kill.
+    HeadOn(PlayerIndex),
+}
+
+impl CrashCause {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CrashCause::Wall => "wall",
+            CrashCause::OwnTrail => "own_trail",
+            CrashCause::Trail(_) => "trail",
+            CrashCause::HeadOn(_) => "head_on",
+        }
+    }
+
+    /// The player whose line or head it was
+    pub fn other_player(&self) -> Option<PlayerIndex> {
+        match self {
+            CrashCause::Trail(i) | CrashCause::HeadOn(i) => Some(*i),
+            CrashCause::Wall | CrashCause::OwnTrail => None,
+        }
+    }
+}
+
+/// The color that a player's line is drawn in. Each frontend decides what that looks like.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum PlayerColor {
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+    Gray,
+    LightRed,
+    LightGreen,
+    LightYellow,
+    LightBlue,
+    LightMagenta,
+    LightCyan,
 }
 
 #[derive(Debug, Clone)]
 pub struct Player {
     pub name: String,
-    pub color: Color,
+    pub color: PlayerColor,
     pub line: Vec<Point>,
     pub direction: Direction,
     pub score: u32,
+    /// Players that crashed into this player's line, in this round
+    pub kills: u32,
     pub crashed: bool,
+    /// The speed without effects, lower than `NORMAL_SPEED` for a handicap
+    pub base_speed: u32,
+    /// What is changing the speed right now, and for how many more frames
+    pub effect: Option<(SpeedEffect, u32)>,
+    /// Frames until the player can boost again
+    pub boost_cooldown: u32,
+    // Percent of a cell travelled but not moved yet
+    progress: u32,
+    // Cells moved in the last frame
+    moved: usize,
+    // Whether the player has boosted since the last frame
+    boosted: bool,
 }
 
 impl Player {
-    pub fn new(name: String, color: Color, start_position: (Point, Direction)) -> Self {
+    pub fn new(name: String, color: PlayerColor, start_position: (Point, Direction)) -> Self {
         Self {
             name,
             color,
             line: vec![start_position.0],
             direction: start_position.1,
             score: 0,
+            kills: 0,
             crashed: false,
+            base_speed: NORMAL_SPEED,
+            effect: None,
+            boost_cooldown: 0,
+            progress: 0,
+            moved: 0,
+            boosted: false,
+        }
+    }
+
+    /// Turns the player, unless that would send it straight back into its own neck. The same
+    /// rule is applied to local and remote players, so that online peers stay in sync. A player
+    /// that hasn't moved since the last turn is still judged by the way it last moved.
+    pub fn steer(&mut self, direction: Direction) -> bool {
+        if !self.can_steer(direction) {
+            return false;
+        }
+        self.direction = direction;
+        true
+    }
+
+    /// Whether `steer` would take the turn
+    pub fn can_steer(&self, direction: Direction) -> bool {
+        let (head, previous_head) = (self.head(), self.previous_head());
+        let last_step = (head.0 - previous_head.0, head.1 - previous_head.1);
+        !is_reversal(self.direction, direction) && !is_reversal(last_step, direction)
+    }
+
+    /// The cells that the player moved into in the last frame, 0 to 2 of them, the head last
+    pub fn moved_cells(&self) -> &[Point] {
+        &self.line[self.line.len() - self.moved..]
+    }
+
+    /// Cells per frame, in percent
+    pub fn speed(&self) -> u32 {
+        let factor = self.effect.map_or(100, |(effect, _)| effect.factor());
+        (self.base_speed * factor / 100).min(MAX_SPEED)
+    }
+
+    pub fn can_boost(&self) -> bool {
+        self.boost_cooldown == 0 && !self.crashed
+    }
+
+    /// Doubles the speed for a few frames, unless the last boost is still cooling down. The
+    /// same rule is applied to local and remote players, so that online peers stay in sync.
+    pub fn boost(&mut self) -> bool {
+        if !self.can_boost() {
+            return false;
+        }
+        self.affect(SpeedEffect::Boost);
+        self.boost_cooldown = BOOST_COOLDOWN_FRAMES;
+        self.boosted = true;
+        true
+    }
+
+    fn affect(&mut self, effect: SpeedEffect) {
+        self.effect = Some((effect, effect.frames()));
+    }
+
+    // How many cells to move in this frame
+    fn travel(&mut self) -> u32 {
+        self.progress += self.speed();
+        let cells = (self.progress / 100).min(MAX_CELLS_PER_FRAME);
+        self.progress -= cells * 100;
+        cells
+    }
+
+    fn wear_off(&mut self) {
+        self.boost_cooldown = self.boost_cooldown.saturating_sub(1);
+        if let Some((_, frames)) = &mut self.effect {
+            *frames -= 1;
+            if *frames == 0 {
+                self.effect = None;
+            }
         }
     }
 
     fn advance_one_step(&mut self) {
         self.line.push(self.next_position());
         self.score += 1;
+        self.moved += 1;
     }
 
     fn next_position(&self) -> Point {
@@ -145,8 +606,314 @@ impl Player {
     fn tail(&self) -> &[Point] {
         &self.line[..self.line.len() - 1]
     }
+
+    // Where the head was before the last step. A line that hasn't moved yet has no other cell.
+    fn previous_head(&self) -> Point {
+        self.line[self.line.len().saturating_sub(2)]
+    }
+}
+
+// Mixes the bits of a number, so that consecutive numbers give unrelated results (SplitMix64)
+fn scramble(n: u64) -> u64 {
+    let mut z = n.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
 }
 
 pub fn translated(point: Point, direction: Direction) -> Point {
     (point.0 + direction.0, point.1 + direction.1)
 }
+
+pub fn turned_left(direction: Direction) -> Direction {
+    (direction.1, -direction.0)
+}
+
+pub fn turned_right(direction: Direction) -> Direction {
+    (-direction.1, direction.0)
+}
+
+pub fn is_reversal(from: Direction, to: Direction) -> bool {
+    from.0 == -to.0 && from.1 == -to.1
+}
+
+/// `up`, `left`, `down` or `right`, as used in the JSON that bots and tools are sent
+pub fn direction_name(direction: Direction) -> &'static str {
+    match direction {
+        UP => "up",
+        LEFT => "left",
+        DOWN => "down",
+        RIGHT => "right",
+        _ => panic!("Invalid direction: {:?}", direction),
+    }
+}
+
+pub fn parse_direction(name: &str) -> Option<Direction> {
+    match &name.to_ascii_lowercase()[..] {
+        "up" => Some(UP),
+        "left" => Some(LEFT),
+        "down" => Some(DOWN),
+        "right" => Some(RIGHT),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use CrashCause::*;
+    use FrameEvent::*;
+
+    // A player whose line is `line`, the head last
+    fn player(line: &[Point], direction: Direction) -> Player {
+        let mut player = Player::new(String::new(), PlayerColor::Blue, (line[0], direction));
+        player.line = line.to_vec();
+        player
+    }
+
+    fn game(players: Vec<Player>) -> Game {
+        Game::new((10, 10), players, 1)
+    }
+
+    #[test]
+    fn two_turns_before_a_move_can_not_reverse_into_the_neck() {
+        let mut player = player(&[(5, 5), (6, 5)], RIGHT);
+        assert!(player.steer(UP));
+        assert!(!player.can_steer(LEFT));
+        assert!(!player.steer(LEFT));
+        assert_eq!(player.direction, UP);
+    }
+
+    #[test]
+    fn a_turn_straight_back_is_turned_down() {
+        let mut player = player(&[(5, 5)], RIGHT);
+        assert!(!player.steer(LEFT));
+        assert!(player.steer(DOWN));
+        assert_eq!(player.direction, DOWN);
+    }
+
+    #[test]
+    fn a_boost_is_an_event_of_the_frame_it_goes_into_effect_in() {
+        let mut game = game(vec![player(&[(1, 2)], RIGHT), player(&[(1, 6)], RIGHT)]);
+        assert!(game.players[1].boost());
+        assert!(!game.players[1].boost());
+        assert_eq!(game.run_frame(), [Boosted(1)]);
+        assert_eq!(game.players[1].head(), (3, 6));
+        assert_eq!(game.run_frame(), []);
+    }
+
+    #[test]
+    fn players_that_move_into_the_same_cell_both_crash() {
+        let mut game = game(vec![player(&[(2, 5)], RIGHT), player(&[(4, 5)], LEFT)]);
+        let events = game.run_frame();
+        assert_eq!(
+            events,
+            [
+                PlayerCrashed(0, HeadOn(1)),
+                PlayerCrashed(1, HeadOn(0)),
+                EveryoneCrashed
+            ]
+        );
+    }
+
+    #[test]
+    fn players_that_swap_cells_both_crash() {
+        let mut game = game(vec![player(&[(2, 5)], RIGHT), player(&[(3, 5)], LEFT)]);
+        let events = game.run_frame();
+        assert_eq!(
+            events,
+            [
+                PlayerCrashed(0, HeadOn(1)),
+                PlayerCrashed(1, HeadOn(0)),
+                EveryoneCrashed
+            ]
+        );
+    }
+
+    #[test]
+    fn moving_into_a_line_is_a_kill_for_its_owner() {
+        let mut game = game(vec![
+            player(&[(4, 4)], RIGHT),
+            player(&[(5, 3), (5, 4), (5, 5)], DOWN),
+        ]);
+        let events = game.run_frame();
+        assert_eq!(events, [PlayerCrashed(0, Trail(1)), PlayerWon(1)]);
+        assert_eq!(game.players[1].kills, 1);
+    }
+
+    #[test]
+    fn the_cell_that_a_player_just_left_is_still_its_line() {
+        // The second player's head moves on from (5, 4) as the first one moves into it
+        let mut game = game(vec![
+            player(&[(4, 4)], RIGHT),
+            player(&[(5, 5), (5, 4)], UP),
+        ]);
+        let events = game.run_frame();
+        assert_eq!(events, [PlayerCrashed(0, Trail(1)), PlayerWon(1)]);
+        assert_eq!(game.players[1].head(), (5, 3));
+    }
+
+    #[test]
+    fn the_order_of_the_players_does_not_matter() {
+        let mut game = game(vec![
+            player(&[(5, 5), (5, 4)], UP),
+            player(&[(4, 4)], RIGHT),
+        ]);
+        let events = game.run_frame();
+        assert_eq!(events, [PlayerCrashed(1, Trail(0)), PlayerWon(0)]);
+        assert_eq!(game.players[0].kills, 1);
+    }
+
+    #[test]
+    fn a_cell_that_was_taken_before_the_step_is_crashed_into_rather_than_a_head() {
+        // Both move into the first player's line, which is what they both crash into
+        for players in [
+            vec![
+                player(&[(3, 5), (4, 5)], RIGHT),
+                player(&[(3, 3), (3, 4)], DOWN),
+                player(&[(2, 6), (3, 6)], UP),
+            ],
+            vec![
+                player(&[(2, 6), (3, 6)], UP),
+                player(&[(3, 3), (3, 4)], DOWN),
+                player(&[(3, 5), (4, 5)], RIGHT),
+            ],
+        ] {
+            let owner = players.iter().position(|p| p.direction == RIGHT).unwrap();
+            let mut game = game(players);
+            game.run_frame();
+            for (i, player) in game.players.iter().enumerate() {
+                assert_eq!(player.crashed, i != owner);
+            }
+            assert_eq!(game.players[owner].kills, 2);
+        }
+    }
+
+    #[test]
+    fn three_players_that_move_into_the_same_cell_all_crash() {
+        let mut game = game(vec![
+            player(&[(4, 5)], RIGHT),
+            player(&[(6, 5)], LEFT),
+            player(&[(5, 6)], UP),
+        ]);
+        let events = game.run_frame();
+        assert_eq!(events.len(), 4);
+        for (i, event) in events[..3].iter().enumerate() {
+            assert!(
+                matches!(event, PlayerCrashed(crashed, HeadOn(other)) if *crashed == i && *other != i),
+                "{:?}",
+                event
+            );
+        }
+        assert_eq!(events[3], EveryoneCrashed);
+    }
+
+    #[test]
+    fn the_head_of_a_crashed_player_is_part_of_its_line() {
+        let mut crashed = player(&[(4, 5), (5, 5)], RIGHT);
+        crashed.crashed = true;
+        let mut game = game(vec![
+            crashed,
+            player(&[(5, 3), (5, 4)], DOWN),
+            player(&[(0, 0)], RIGHT),
+        ]);
+        let events = game.run_frame();
+        assert_eq!(events, [PlayerCrashed(1, Trail(0)), PlayerWon(2)]);
+        assert_eq!(game.players[0].kills, 1);
+    }
+
+    #[test]
+    fn a_fast_player_crashes_where_it_meets_a_slow_one_on_the_way() {
+        let mut fast = player(&[(2, 5)], RIGHT);
+        fast.base_speed = MAX_SPEED;
+        let mut game = game(vec![fast, player(&[(3, 4)], DOWN)]);
+        let events = game.run_frame();
+        assert_eq!(
+            events,
+            [
+                PlayerCrashed(0, HeadOn(1)),
+                PlayerCrashed(1, HeadOn(0)),
+                EveryoneCrashed
+            ]
+        );
+        assert_eq!(game.players[0].moved_cells(), [(3, 5)]);
+    }
+
+    #[test]
+    fn a_fast_player_crashes_into_a_cell_that_a_slow_one_moved_into_a_step_earlier() {
+        let mut fast = player(&[(2, 5)], RIGHT);
+        fast.base_speed = MAX_SPEED;
+        let mut game = game(vec![fast, player(&[(4, 4)], DOWN)]);
+        let events = game.run_frame();
+        assert_eq!(events, [PlayerCrashed(0, Trail(1)), PlayerWon(1)]);
+        assert_eq!(game.players[0].moved_cells(), [(3, 5), (4, 5)]);
+    }
+
+    #[test]
+    fn a_fast_player_crashes_into_a_line_it_would_have_jumped_over() {
+        let mut fast = player(&[(2, 5)], RIGHT);
+        fast.base_speed = MAX_SPEED;
+        let mut game = game(vec![fast, player(&[(3, 5), (3, 6), (3, 7)], DOWN)]);
+        let events = game.run_frame();
+        assert_eq!(events, [PlayerCrashed(0, Trail(1)), PlayerWon(1)]);
+        assert_eq!(game.players[0].head(), (3, 5));
+    }
+
+    // The last two players crash head-on, with the given kills and lines of the given lengths
+    fn tie(rule: TieRule, kills: [u32; 2], lengths: [i32; 2]) -> FrameEvent {
+        let first = (0..lengths[0])
+            .map(|i| (2 - i, 5))
+            .rev()
+            .collect::<Vec<_>>();
+        let second = (0..lengths[1])
+            .map(|i| (4 + i, 5))
+            .rev()
+            .collect::<Vec<_>>();
+        let mut game = game(vec![player(&first, RIGHT), player(&second, LEFT)]);
+        game.tie_rule = rule;
+        for (player, kills) in game.players.iter_mut().zip(kills) {
+            player.kills = kills;
+        }
+        game.run_frame().pop().unwrap()
+    }
+
+    #[test]
+    fn a_draw_has_no_winner() {
+        assert_eq!(tie(TieRule::Draw, [1, 0], [3, 1]), EveryoneCrashed);
+        assert_eq!(tie(TieRule::Draw, [0, 0], [1, 1]), EveryoneCrashed);
+    }
+
+    #[test]
+    fn the_player_with_the_most_kills_wins_a_tie() {
+        assert_eq!(tie(TieRule::MostKills, [0, 2], [3, 1]), PlayerWon(1));
+        assert_eq!(tie(TieRule::MostKills, [1, 0], [1, 3]), PlayerWon(0));
+        assert_eq!(tie(TieRule::MostKills, [1, 1], [3, 1]), EveryoneCrashed);
+    }
+
+    #[test]
+    fn the_player_with_the_longest_line_wins_a_tie() {
+        assert_eq!(tie(TieRule::LongestLine, [0, 2], [3, 1]), PlayerWon(0));
+        assert_eq!(tie(TieRule::LongestLine, [2, 0], [1, 3]), PlayerWon(1));
+        assert_eq!(tie(TieRule::LongestLine, [1, 0], [2, 2]), EveryoneCrashed);
+    }
+
+    #[test]
+    fn only_the_players_that_crashed_last_are_in_a_tie() {
+        // The third player crashes a frame before the others, with the longest line
+        let mut game = game(vec![
+            player(&[(2, 5)], RIGHT),
+            player(&[(5, 5)], LEFT),
+            player(&[(9, 0), (9, 1), (9, 2), (9, 3), (9, 4)], RIGHT),
+        ]);
+        game.tie_rule = TieRule::LongestLine;
+        assert_eq!(game.run_frame(), [PlayerCrashed(2, Wall)]);
+        assert_eq!(
+            game.run_frame(),
+            [
+                PlayerCrashed(0, HeadOn(1)),
+                PlayerCrashed(1, HeadOn(0)),
+                EveryoneCrashed
+            ]
+        );
+    }
+}
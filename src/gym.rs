@@ -0,0 +1,450 @@
+use crate::ai::{AiKind, AiStrategy};
+use crate::config::MAX_SEATS;
+use crate::game::{self, FrameEvent, Game, Player, PlayerColor, PlayerIndex, StartPosition};
+use rand::rngs::SmallRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::time::Duration;
+
+/// What an agent does in a step. Turns are relative to where it's heading, so that there is no
+/// way to ask for a reversal.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Action {
+    Straight,
+    TurnLeft,
+    TurnRight,
+}
+
+/// A plane of an observation, with a 1 for every cell that has the thing in it and 0 elsewhere
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Channel {
+    /// The agent's own line, head included
+    OwnTrail,
+    /// Every other player's line, heads included
+    OtherTrails,
+    OwnHead,
+    /// The heads of the other players that are still alive
+    OtherHeads,
+    /// The border around the arena
+    Walls,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 5] = [
+        Channel::OwnTrail,
+        Channel::OtherTrails,
+        Channel::OwnHead,
+        Channel::OtherHeads,
+        Channel::Walls,
+    ];
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Rewards {
+    /// For every step that an agent survives
+    pub alive: f32,
+    /// For being the last one alive
+    pub win: f32,
+    /// For the step that an agent crashes in
+    pub crash: f32,
+}
+
+impl Default for Rewards {
+    fn default() -> Self {
+        Self {
+            alive: 0.0,
+            win: 1.0,
+            crash: -1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EnvConfig {
+    pub size: (u16, u16),
+    /// Players that are steered through `Env::step`. They are the first players of the game.
+    pub agents: usize,
+    /// Players that are steered by built-in strategies, seated after the agents
+    pub opponents: Vec<AiKind>,
+    /// How long the search based opponents may think per step
+    pub opponent_think_time: Duration,
+    /// The planes of each observation, in order
+    pub channels: Vec<Channel>,
+    pub rewards: Rewards,
+}
+
+impl Default for EnvConfig {
+    fn default() -> Self {
+        Self {
+            size: (35, 16),
+            agents: 1,
+            opponents: vec![AiKind::Greedy],
+            opponent_think_time: Duration::from_millis(1),
+            channels: Channel::ALL.to_vec(),
+            rewards: Rewards::default(),
+        }
+    }
+}
+
+/// What one agent sees: a stack of `channels` planes, each `height` rows of `width` cells, laid
+/// out channel by channel and row by row. The planes are one cell bigger than the arena on every
+/// side, so that the walls can be seen, i.e. the arena cell (x, y) is at (x + 1, y + 1).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Observation {
+    pub channels: usize,
+    pub height: usize,
+    pub width: usize,
+    pub data: Vec<f32>,
+}
+
+impl Observation {
+    pub fn get(&self, channel: usize, x: usize, y: usize) -> f32 {
+        self.data[(channel * self.height + y) * self.width + x]
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Info {
+    pub frame: u32,
+    /// Whether each agent has crashed, this step or before
+    pub crashed: Vec<bool>,
+    /// The last player alive, which may also be an opponent
+    pub winner: Option<PlayerIndex>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Step {
+    /// One per agent, also for the ones that have crashed
+    pub observations: Vec<Observation>,
+    pub rewards: Vec<f32>,
+    /// The round is over, or every agent has crashed
+    pub done: bool,
+    pub info: Info,
+}
+
+/// A gym-style environment for training agents to play the game: `reset` starts a round and
+/// `step` runs one frame of it with an action from every agent. It runs the game as fast as it
+/// can, with no tick, UI or networking involved.
+pub struct Env {
+    config: EnvConfig,
+    game: Game,
+    opponents: Vec<Box<dyn AiStrategy>>,
+    winner: Option<PlayerIndex>,
+}
+
+impl Env {
+    /// Fails unless there is at least one agent, and there are seats for the agents and
+    /// opponents together (`MAX_SEATS`)
+    pub fn new(config: EnvConfig) -> Result<Self, String> {
+        let players = config.agents + config.opponents.len();
+        if config.agents == 0 {
+            return Err("There has to be an agent".to_string());
+        }
+        if players > MAX_SEATS {
+            return Err(format!(
+                "There are at most {} seats for agents and opponents (got {})",
+                MAX_SEATS, players
+            ));
+        }
+        let mut env = Self {
+            game: Game::new(config.size, vec![], 1),
+            config,
+            opponents: vec![],
+            winner: None,
+        };
+        env.reset(0);
+        Ok(env)
+    }
+
+    pub fn config(&self) -> &EnvConfig {
+        &self.config
+    }
+
+    pub fn game(&self) -> &Game {
+        &self.game
+    }
+
+    /// Starts a new round and returns what each agent sees. The seed decides who starts where
+    /// and the choices of the random opponents, so the same seed and actions give the same
+    /// round, as long as the search based opponents aren't cut short by their think time.
+    pub fn reset(&mut self, seed: u64) -> Vec<Observation> {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let count = self.config.agents + self.config.opponents.len();
+        let mut start_positions = StartPosition::spread(count);
+        start_positions.shuffle(&mut rng);
+
+        let players = start_positions
+            .iter()
+            .enumerate()
+            .map(|(i, start_position)| {
+                let name = match i.checked_sub(self.config.agents) {
+                    None => format!("Agent {}", i + 1),
+                    Some(opponent_i) => self.config.opponents[opponent_i].name().to_string(),
+                };
+                Player::new(
+                    name,
+                    PlayerColor::Gray,
+                    start_position.resolve(self.config.size),
+                )
+            })
+            .collect();
+        self.game = Game::new(self.config.size, players, 1);
+        self.opponents = self
+            .config
+            .opponents
+            .iter()
+            .map(|kind| kind.create_seeded(self.config.opponent_think_time, rng.gen()))
+            .collect();
+        self.winner = None;
+
+        self.observations()
+    }
+
+    /// Runs one frame, with one action per agent. The actions of agents that have crashed are
+    /// ignored.
+    pub fn step(&mut self, actions: &[Action]) -> Step {
+        assert_eq!(actions.len(), self.config.agents, "One action per agent");
+        let agents = self.config.agents;
+        let rewards = &self.config.rewards;
+        if self.is_done() {
+            return Step {
+                observations: self.observations(),
+                rewards: vec![0.0; agents],
+                done: true,
+                info: self.info(),
+            };
+        }
+
+        for (player, action) in self.game.players.iter_mut().zip(actions) {
+            if !player.crashed {
+                let direction = match action {
+                    Action::Straight => player.direction,
+                    Action::TurnLeft => game::turned_left(player.direction),
+                    Action::TurnRight => game::turned_right(player.direction),
+                };
+                player.steer(direction);
+            }
+        }
+        for (i, strategy) in self.opponents.iter_mut().enumerate() {
+            let player_i = agents + i;
+            if !self.game.players[player_i].crashed {
+                let direction = strategy.choose_direction(&self.game, player_i);
+                self.game.players[player_i].steer(direction);
+            }
+        }
+
+        let alive_before: Vec<bool> = self.game.players[..agents]
+            .iter()
+            .map(|p| !p.crashed)
+            .collect();
+        for event in self.game.run_frame() {
+            if let FrameEvent::PlayerWon(player_i) = event {
+                self.winner = Some(player_i);
+            }
+        }
+        let step_rewards = (0..agents)
+            .map(|i| {
+                if !alive_before[i] {
+                    0.0
+                } else if self.game.players[i].crashed {
+                    rewards.crash
+                } else if self.winner == Some(i) {
+                    rewards.alive + rewards.win
+                } else {
+                    rewards.alive
+                }
+            })
+            .collect();
+
+        Step {
+            observations: self.observations(),
+            rewards: step_rewards,
+            done: self.is_done(),
+            info: self.info(),
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.game.game_over
+            || self.game.players[..self.config.agents]
+                .iter()
+                .all(|p| p.crashed)
+    }
+
+    fn info(&self) -> Info {
+        Info {
+            frame: self.game.frame,
+            crashed: self.game.players[..self.config.agents]
+                .iter()
+                .map(|p| p.crashed)
+                .collect(),
+            winner: self.winner,
+        }
+    }
+
+    fn observations(&self) -> Vec<Observation> {
+        (0..self.config.agents).map(|i| self.observe(i)).collect()
+    }
+
+    fn observe(&self, agent: PlayerIndex) -> Observation {
+        let (width, height) = (
+            self.config.size.0 as usize + 2,
+            self.config.size.1 as usize + 2,
+        );
+        let mut observation = Observation {
+            channels: self.config.channels.len(),
+            height,
+            width,
+            data: vec![0.0; self.config.channels.len() * height * width],
+        };
+        let plane = width * height;
+        // A head that crashed into a wall is out of the arena, and isn't shown
+        let index = |(x, y): (i32, i32)| {
+            let inside = x >= 0 && y >= 0 && (x as usize) < width - 2 && (y as usize) < height - 2;
+            inside.then(|| (y as usize + 1) * width + x as usize + 1)
+        };
+
+        for (c, channel) in self.config.channels.iter().enumerate() {
+            let cells = &mut observation.data[c * plane..(c + 1) * plane];
+            match channel {
+                Channel::OwnTrail | Channel::OtherTrails => {
+                    let own = *channel == Channel::OwnTrail;
+                    for (i, player) in self.game.players.iter().enumerate() {
+                        if (i == agent) == own {
+                            for cell in player.line.iter().filter_map(|p| index(*p)) {
+                                cells[cell] = 1.0;
+                            }
+                        }
+                    }
+                }
+                Channel::OwnHead => {
+                    if let Some(cell) = index(self.game.players[agent].head()) {
+                        cells[cell] = 1.0;
+                    }
+                }
+                Channel::OtherHeads => {
+                    for (i, player) in self.game.players.iter().enumerate() {
+                        if i != agent && !player.crashed {
+                            if let Some(cell) = index(player.head()) {
+                                cells[cell] = 1.0;
+                            }
+                        }
+                    }
+                }
+                Channel::Walls => {
+                    for y in 0..height {
+                        for x in 0..width {
+                            if x == 0 || y == 0 || x == width - 1 || y == height - 1 {
+                                cells[y * width + x] = 1.0;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        observation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(agents: usize, opponents: Vec<AiKind>) -> Env {
+        Env::new(EnvConfig {
+            size: (12, 8),
+            agents,
+            opponents,
+            ..EnvConfig::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn an_observation_has_the_arena_inside_a_border() {
+        let mut env = env(1, vec![AiKind::Greedy]);
+        let observation = &env.reset(0)[0];
+        let channel = |wanted| Channel::ALL.iter().position(|c| *c == wanted).unwrap();
+        let (x, y) = env.game().players[0].head();
+        let (x, y) = (x as usize + 1, y as usize + 1);
+        assert_eq!((observation.width, observation.height), (14, 10));
+        assert_eq!(observation.get(channel(Channel::OwnHead), x, y), 1.0);
+        assert_eq!(observation.get(channel(Channel::OwnTrail), x, y), 1.0);
+        assert_eq!(observation.get(channel(Channel::OtherTrails), x, y), 0.0);
+        let (x, y) = env.game().players[1].head();
+        let (x, y) = (x as usize + 1, y as usize + 1);
+        assert_eq!(observation.get(channel(Channel::OtherHeads), x, y), 1.0);
+
+        let walls = channel(Channel::Walls);
+        assert_eq!(observation.get(walls, 0, 0), 1.0);
+        assert_eq!(observation.get(walls, 13, 9), 1.0);
+        assert_eq!(observation.get(walls, 1, 1), 0.0);
+        assert_eq!(observation.get(walls, 12, 8), 0.0);
+        let cells: f32 = observation.data[walls * 140..(walls + 1) * 140]
+            .iter()
+            .sum();
+        assert_eq!(cells, (14 * 2 + 8 * 2) as f32);
+    }
+
+    #[test]
+    fn the_same_seed_and_actions_give_the_same_round() {
+        let actions = [Action::Straight, Action::TurnLeft, Action::TurnRight];
+        let play = |seed| {
+            let mut env = env(1, vec![AiKind::RandomSafe, AiKind::Greedy]);
+            let mut steps = vec![env.reset(seed)];
+            for i in 0.. {
+                let step = env.step(&[actions[i % 3]]);
+                steps.push(step.observations);
+                if step.done {
+                    break;
+                }
+            }
+            steps
+        };
+        assert_eq!(play(7), play(7));
+    }
+
+    #[test]
+    fn crashing_is_rewarded_once_and_ends_the_round_for_a_single_agent() {
+        let mut env = env(1, vec![AiKind::Greedy]);
+        env.reset(0);
+        let mut step = env.step(&[Action::Straight]);
+        while !step.done {
+            assert_eq!(step.rewards, [0.0]);
+            // Spinning on the spot runs into the agent's own line
+            step = env.step(&[Action::TurnLeft]);
+        }
+        assert_eq!(step.rewards, [-1.0]);
+        assert_eq!(step.info.crashed, [true]);
+        assert_ne!(step.info.winner, Some(0));
+        let step = env.step(&[Action::Straight]);
+        assert!(step.done);
+        assert_eq!(step.rewards, [0.0]);
+    }
+
+    #[test]
+    fn the_last_agent_alive_is_rewarded_for_the_win() {
+        let mut env = env(2, vec![]);
+        env.reset(0);
+        // One agent keeps turning left into its own line while the other goes straight
+        let mut step = env.step(&[Action::Straight, Action::Straight]);
+        while !step.done {
+            step = env.step(&[Action::TurnLeft, Action::Straight]);
+        }
+        assert_eq!(step.info.winner, Some(1));
+        assert_eq!(step.rewards, [-1.0, 1.0]);
+        assert_eq!(step.info.crashed, [true, false]);
+    }
+
+    #[test]
+    fn there_have_to_be_seats_for_everyone() {
+        let config = |agents, opponents| EnvConfig {
+            agents,
+            opponents: vec![AiKind::Greedy; opponents],
+            ..EnvConfig::default()
+        };
+        assert!(Env::new(config(0, 2)).is_err());
+        assert!(Env::new(config(2, MAX_SEATS - 1)).is_err());
+        assert!(Env::new(config(2, MAX_SEATS - 2)).is_ok());
+    }
+}
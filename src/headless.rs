@@ -1,134 +1,422 @@
-use crate::app::ThreadMessage;
-use crate::game::{Game, Player, DOWN, LEFT, RIGHT, UP};
-use crate::net::{NetworkEvent, Networking, Outcome};
-use std::io::{stdout, Write};
-use std::net::TcpStream;
-use std::sync::mpsc;
-use std::sync::mpsc::TryRecvError;
-use tui::style::Color;
-
-pub fn run(socket: TcpStream) {
-    let frame = 1;
-
-    let remote_player_i = 0;
-    let local_player_i = 1;
-    let local_direction = LEFT;
-    let local_player_name = "Headless client".to_string();
-    let (mut networking, game_info) = Networking::join(
-        socket,
-        local_player_i,
-        remote_player_i,
-        local_direction,
-        frame,
-        local_player_name.clone(),
-    );
-
-    println!("Game info: {:?}", game_info);
-
-    let size = game_info.size;
-    let remote_player = Player::new(
-        game_info.remote_player_name,
-        Color::Blue,
-        ((1, (size.1 / 2) as i32), RIGHT),
-    );
-    let local_player = Player::new(
-        local_player_name,
-        Color::Green,
-        (((size.0 - 2) as i32, (size.1 / 2) as i32), local_direction),
-    );
-
-    let players = vec![remote_player, local_player];
-
-    let mut game = Game::new(size, players, frame);
-
-    let (sender, receiver) = mpsc::channel();
-
-    networking.start_game(sender).unwrap();
-
-    let mut input = String::new();
-    let stdin = std::io::stdin();
-    while !game.game_over {
-        println!("~~ frame {} ~~", game.frame);
-        match receiver.try_recv() {
-            Ok(ThreadMessage::Network(event)) => match event {
-                NetworkEvent::BufferedOutcomes => {
-                    let outcomes = networking.take_buffered_outcomes();
-                    execute_outcomes(&mut game, &mut networking, outcomes);
+use crate::ai::AiStrategy;
+use crate::game::{
+    self, direction_name, Direction, FrameEvent, Game, Player, PlayerColor, PlayerIndex,
+    StartPosition, DOWN, LEFT, RIGHT, UP,
+};
+use crate::net::{GameInfo, NetStats, Networking, Outcome, Rules};
+use crate::transport::Transport;
+use anyhow::{anyhow, Context, Result};
+use serde_json::{json, Value};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::time::{self, MissedTickBehavior};
+
+const FIRST_FRAME: u32 = 1;
+const PLAYER_COLORS: [PlayerColor; 2] = [PlayerColor::Blue, PlayerColor::Green];
+
+/// Where the moves of a headless player come from
+pub enum Driver {
+    /// Lines typed on stdin (see `Driver::script` for what they can say), each one applied to the
+    /// next frame that is open. `q` quits.
+    Stdin,
+    /// One move per frame, in order
+    Script(VecDeque<Option<Direction>>),
+    Ai(Box<dyn AiStrategy>),
+}
+
+impl Driver {
+    /// Reads a script with one move per line, and so per frame: a direction (`up` or `w`, `left`
+    /// or `a`, `down` or `s`, `right` or `d`), or `-` or nothing to keep going. Anything after a
+    /// `#` is a comment, and lines with only a comment don't count. When the script runs out,
+    /// the player keeps going.
+    pub fn script(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read script {:?}", path))?;
+        let moves = text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim_start().starts_with('#'))
+            .map(|(i, line)| {
+                let line = line.split('#').next().unwrap_or_default().trim();
+                match line {
+                    "" | "-" => Ok(None),
+                    _ => parse_move(line).map(Some).ok_or_else(|| {
+                        anyhow!("Unknown move '{}' on line {} of {:?}", line, i + 1, path)
+                    }),
+                }
+            })
+            .collect::<Result<_>>()?;
+        Ok(Driver::Script(moves))
+    }
+}
+
+/// A player in an online game without a terminal UI, for testing the protocol and for running
+/// games with no one at the keyboard. It commits a frame every tick, like the app does, and
+/// logs every outcome and frame to stdout, as text or as JSON lines. It plays a single round.
+pub struct Headless {
+    pub name: String,
+    pub driver: Driver,
+    pub tick: Duration,
+    pub json: bool,
+}
+
+impl Headless {
+    pub async fn host(self, socket: Transport, rules: Rules) -> Result<()> {
+        let start_positions = StartPosition::spread(2);
+        let (networking, game_info) = Networking::host(
+            socket,
+            0,
+            1,
+            start_positions[0].direction(),
+            FIRST_FRAME,
+            rules,
+            self.name.clone(),
+        )
+        .await
+        .context("Handshake with the client failed")?;
+        self.play(networking, game_info).await
+    }
+
+    pub async fn join(self, socket: Transport) -> Result<()> {
+        let start_positions = StartPosition::spread(2);
+        let (networking, game_info) = Networking::join(
+            socket,
+            1,
+            0,
+            start_positions[1].direction(),
+            FIRST_FRAME,
+            self.name.clone(),
+        )
+        .await
+        .context("Handshake with the host failed")?;
+        self.play(networking, game_info).await
+    }
+
+    async fn play(mut self, mut networking: Networking, game_info: GameInfo) -> Result<()> {
+        let size = game_info.rules.size;
+        let local_player_i = networking.local_player_index();
+        let start_positions = StartPosition::spread(2);
+        let players = (0..2)
+            .map(|i| {
+                let name = if i == local_player_i {
+                    self.name.clone()
+                } else {
+                    game_info.remote_player_name.clone()
+                };
+                Player::new(name, PLAYER_COLORS[i], start_positions[i].resolve(size))
+            })
+            .collect();
+        let mut game = Game::new(size, players, FIRST_FRAME);
+        game.tie_rule = game_info.rules.tie_rule;
+        game.spawn_power_ups = game_info.rules.power_ups;
+        let log = Log {
+            json: self.json,
+            winner: Cell::new(None),
+        };
+        log.start(&game, local_player_i);
+
+        let mut stdin_lines = match self.driver {
+            Driver::Stdin => Some(read_stdin_lines()),
+            _ => None,
+        };
+        let mut typed_moves = VecDeque::new();
+
+        let (mut network_events, outcomes) = networking.start_game();
+        execute_outcomes(&mut game, &mut networking, outcomes, &log);
+
+        let mut clock = time::interval(self.tick);
+        clock.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        while !game.game_over {
+            tokio::select! {
+                _ = clock.tick() => {
+                    // One move per frame, made before the frame is committed
+                    if networking.is_frame_open() && !game.players[local_player_i].crashed {
+                        if let Some(direction) =
+                            self.next_move(&game, local_player_i, &mut typed_moves)
+                        {
+                            let player = &game.players[local_player_i];
+                            if direction != player.direction && player.can_steer(direction) {
+                                let outcomes = networking.set_direction(direction);
+                                execute_outcomes(&mut game, &mut networking, outcomes, &log);
+                            }
+                        }
+                    }
+                    let outcomes = networking.commit_frame();
+                    execute_outcomes(&mut game, &mut networking, outcomes, &log);
                 }
-                NetworkEvent::ReceiveError(error) => {
-                    panic!("Network error: {:?}", error);
+                event = network_events.recv() => {
+                    let event = event.ok_or_else(|| anyhow!("The network tasks died"))?;
+                    let outcomes = networking.handle_event(event).context("Network error")?;
+                    execute_outcomes(&mut game, &mut networking, outcomes, &log);
                 }
-            },
-            Ok(ThreadMessage::UserInput(event)) => {
-                panic!("Headless didn't expect user input: {:?}", event)
-            }
-            Ok(ThreadMessage::Tick) => panic!("No tick in headless"),
-            Err(TryRecvError::Empty) => {}
-            Err(TryRecvError::Disconnected) => {
-                panic!("Network reader thread died")
+                line = next_line(&mut stdin_lines) => match line {
+                    Some(line) if line.trim() == "q" => break,
+                    Some(line) => match parse_move(line.trim()) {
+                        Some(direction) => typed_moves.push_back(direction),
+                        None => log.problem(&format!("Unknown move '{}'", line.trim())),
+                    },
+                    // Stdin has been closed, but that's no reason to stop playing
+                    None => stdin_lines = None,
+                },
             }
         }
 
-        print!("> ");
-        stdout().flush().unwrap();
-        input.clear();
-        stdin.read_line(&mut input).unwrap();
-        input.make_ascii_lowercase();
-
-        let input_direction = if input.starts_with('w') {
-            Some(UP)
-        } else if input.starts_with('a') {
-            Some(LEFT)
-        } else if input.starts_with('s') {
-            Some(DOWN)
-        } else if input.starts_with('d') {
-            Some(RIGHT)
-        } else if input.starts_with('q') {
-            break;
-        } else {
-            None
-        };
+        log.end(&game, &networking.stats());
+        networking.exit().await;
+        Ok(())
+    }
 
-        if let Some(direction) = input_direction {
-            let outcomes = networking.set_direction(direction).unwrap();
-            execute_outcomes(&mut game, &mut networking, outcomes);
+    fn next_move(
+        &mut self,
+        game: &Game,
+        player_i: PlayerIndex,
+        typed_moves: &mut VecDeque<Direction>,
+    ) -> Option<Direction> {
+        match &mut self.driver {
+            Driver::Stdin => typed_moves.pop_front(),
+            Driver::Script(moves) => moves.pop_front().flatten(),
+            Driver::Ai(strategy) => Some(strategy.choose_direction(game, player_i)),
         }
+    }
+}
 
-        println!("Committing frame.");
-        let outcomes = networking.commit_frame().unwrap();
-        execute_outcomes(&mut game, &mut networking, outcomes);
+// `w`, `a`, `s` and `d` work too
+fn parse_move(s: &str) -> Option<Direction> {
+    match &s.to_ascii_lowercase()[..] {
+        "w" => Some(UP),
+        "a" => Some(LEFT),
+        "s" => Some(DOWN),
+        "d" => Some(RIGHT),
+        name => game::parse_direction(name),
     }
+}
 
-    println!("Game over. Press enter to exit.");
-    let mut input = String::new();
-    stdin.read_line(&mut input).unwrap();
+fn read_stdin_lines() -> UnboundedReceiver<String> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
 
-    networking.exit();
+async fn next_line(lines: &mut Option<UnboundedReceiver<String>>) -> Option<String> {
+    match lines {
+        Some(lines) => lines.recv().await,
+        None => std::future::pending().await,
+    }
 }
 
-fn execute_outcomes(game: &mut Game, networking: &mut Networking, outcomes: Vec<Outcome>) {
+fn execute_outcomes(
+    game: &mut Game,
+    networking: &mut Networking,
+    outcomes: Vec<Outcome>,
+    log: &Log,
+) {
     for outcome in outcomes {
-        println!("  outcome: {:?}", outcome);
+        log.outcome(game.frame, &outcome);
         match outcome {
             Outcome::PlayerControl(control) => {
-                game.players[control.player_i].direction = control.direction;
+                game.players[control.player_i].steer(control.direction);
+            }
+            Outcome::PlayerBoost(player_i) => {
+                game.players[player_i].boost();
             }
             Outcome::RunFrame => {
-                println!("  Running frame {}", game.frame);
-
+                let frame = game.frame;
                 let frame_events = game.run_frame();
-                if !frame_events.is_empty() {
-                    println!("  Game events: {:?}", frame_events);
+                log.frame(frame, game, &frame_events, &networking.stats());
+                if game.game_over {
+                    networking.end_round();
                 }
-                println!("  State: {:?}", game.players);
 
-                let outcomes = networking.start_new_frame(game.frame).unwrap();
-                execute_outcomes(game, networking, outcomes);
+                let direction = game.players[networking.local_player_index()].direction;
+                let outcomes = networking.start_new_frame(game.frame, direction);
+                execute_outcomes(game, networking, outcomes, log);
+            }
+            Outcome::Chat { .. } => {}
+            Outcome::RemoteWantsRematch { .. } | Outcome::Rematch { .. } => {
+                // The headless player only plays a single round
+            }
+            Outcome::RemoteLeft { .. } => game.game_over = true,
+        }
+    }
+}
+
+// Text for people, or one JSON object per line for tools
+struct Log {
+    json: bool,
+    // Nobody won if everyone crashed, or if the remote left in the middle of the round
+    winner: Cell<Option<PlayerIndex>>,
+}
+
+impl Log {
+    fn start(&self, game: &Game, local_player_i: PlayerIndex) {
+        if self.json {
+            self.emit(json!({
+                "type": "start",
+                "frame": game.frame,
+                "size": game.size(),
+                "you": local_player_i,
+                "players": game.players.iter().map(|p| &p.name).collect::<Vec<_>>(),
+            }));
+        } else {
+            let (w, h) = game.size();
+            let local = &game.players[local_player_i];
+            let remote = &game.players[1 - local_player_i];
+            println!(
+                "{}x{} arena, {} (player {}) against {}",
+                w, h, local.name, local_player_i, remote.name
+            );
+        }
+    }
+
+    fn outcome(&self, frame: u32, outcome: &Outcome) {
+        if !self.json {
+            println!("  outcome: {:?}", outcome);
+            return;
+        }
+        let details = match outcome {
+            Outcome::PlayerControl(control) => json!({
+                "outcome": "player_control",
+                "player": control.player_i,
+                "direction": direction_name(control.direction),
+            }),
+            Outcome::PlayerBoost(player_i) => json!({
+                "outcome": "player_boost",
+                "player": player_i,
+            }),
+            Outcome::RunFrame => json!({ "outcome": "run_frame" }),
+            Outcome::Chat { player_i, text } => json!({
+                "outcome": "chat",
+                "player": player_i,
+                "text": text,
+            }),
+            Outcome::RemoteWantsRematch { swap_sides } => json!({
+                "outcome": "remote_wants_rematch",
+                "swap_sides": swap_sides,
+            }),
+            Outcome::Rematch { swap_sides, frame } => json!({
+                "outcome": "rematch",
+                "swap_sides": swap_sides,
+                "first_frame": frame,
+            }),
+            Outcome::RemoteLeft { politely } => json!({
+                "outcome": "remote_left",
+                "politely": politely,
+            }),
+        };
+        let mut line = json!({ "type": "outcome", "frame": frame });
+        merge(&mut line, details);
+        self.emit(line);
+    }
+
+    fn frame(&self, frame: u32, game: &Game, events: &[FrameEvent], stats: &NetStats) {
+        for event in events {
+            if let FrameEvent::PlayerWon(i) = event {
+                self.winner.set(Some(*i));
+            }
+        }
+        if !self.json {
+            println!("~~ frame {} ~~", frame);
+            if !events.is_empty() {
+                println!("  Game events: {:?}", events);
             }
-            Outcome::RemoteLeft { .. } => {
-                println!("  They left!");
-                game.game_over = true;
+            println!("  State: {:?}", game.players);
+            println!("  Net stats: {}", stats);
+            return;
+        }
+        let events: Vec<Value> = events
+            .iter()
+            .map(|event| match event {
+                FrameEvent::Boosted(i) => json!({ "event": "boosted", "player": i }),
+                FrameEvent::PlayerCrashed(i, cause) => json!({
+                    "event": "player_crashed",
+                    "player": i,
+                    "cause": cause.name(),
+                    "by": cause.other_player(),
+                }),
+                FrameEvent::PlayerWon(i) => json!({ "event": "player_won", "player": i }),
+                FrameEvent::EveryoneCrashed => json!({ "event": "everyone_crashed" }),
+                FrameEvent::PowerUpTaken(i, power_up) => json!({
+                    "event": "power_up_taken",
+                    "player": i,
+                    "power_up": power_up.name(),
+                }),
+            })
+            .collect();
+        let players: Vec<Value> = game
+            .players
+            .iter()
+            .map(|p| {
+                json!({
+                    "head": p.head(),
+                    "direction": direction_name(p.direction),
+                    "crashed": p.crashed,
+                    "score": p.score,
+                    "speed": p.speed(),
+                })
+            })
+            .collect();
+        self.emit(json!({
+            "type": "frame",
+            "frame": frame,
+            "players": players,
+            "events": events,
+        }));
+    }
+
+    fn end(&self, game: &Game, stats: &NetStats) {
+        let winner = self.winner.get();
+        if !self.json {
+            match winner {
+                Some(i) => println!("Game over, {} won", game.players[i].name),
+                None => println!("Game over"),
             }
+            println!("Net stats: {}", stats);
+            return;
+        }
+        self.emit(json!({
+            "type": "end",
+            "frame": game.frame,
+            "winner": winner,
+            "scores": game.players.iter().map(|p| p.score).collect::<Vec<_>>(),
+            "kills": game.players.iter().map(|p| p.kills).collect::<Vec<_>>(),
+            "stats": {
+                "rtt_ms": stats.rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+                "jitter_ms": stats.jitter.as_secs_f64() * 1000.0,
+                "bytes_sent": stats.bytes_sent,
+                "bytes_received": stats.bytes_received,
+                "frames_waited_on_remote": stats.frames_waited_on_remote,
+                "longest_stall_ms": stats.longest_stall.as_secs_f64() * 1000.0,
+            },
+        }));
+    }
+
+    fn problem(&self, problem: &str) {
+        if self.json {
+            self.emit(json!({ "type": "problem", "message": problem }));
+        } else {
+            println!("{}", problem);
         }
     }
+
+    fn emit(&self, line: Value) {
+        println!("{}", line);
+    }
+}
+
+fn merge(into: &mut Value, from: Value) {
+    if let (Value::Object(into), Value::Object(from)) = (into, from) {
+        into.extend(from);
+    }
 }
@@ -0,0 +1,24 @@
+//! The game engine, AI, network protocol and tools behind the `achtung-rs` binary. The terminal
+//! UI is behind the `tui` feature (on by default); without it, nothing here needs a terminal.
+
+pub mod ai;
+#[cfg(feature = "tui")]
+pub mod app;
+mod auth;
+pub mod bot;
+pub mod config;
+pub mod events;
+pub mod game;
+pub mod gym;
+pub mod headless;
+pub mod lobby;
+pub mod net;
+pub mod replay;
+pub mod stats;
+pub mod tournament;
+pub mod transport;
+#[cfg(feature = "tui")]
+mod user_interface;
+
+/// A cell of the arena, as (x, y) from the top left corner
+pub type Point = (i32, i32);
@@ -0,0 +1,105 @@
+use crate::auth::{self, AuthOutcome};
+use crate::net::with_timeout;
+use crate::transport::Transport;
+use anyhow::{bail, Result};
+use std::io::{self, Write};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Handshakes are done concurrently, so that a stranger who connects and then stays silent
+// doesn't keep the actual opponent waiting. The first client to complete the handshake gets to
+// play.
+pub async fn accept_client(
+    listener: &TcpListener,
+    password: Option<String>,
+    key: Option<String>,
+) -> Result<Transport> {
+    let (sender, mut handshakes) = mpsc::unbounded_channel();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, address) = accepted?;
+                let sender = sender.clone();
+                let password = password.clone();
+                let key = key.clone();
+                tokio::spawn(async move {
+                    let handshake = handshake_with_client(socket, password, key);
+                    let result = match tokio::time::timeout(CONNECT_TIMEOUT, handshake).await {
+                        Ok(result) => result,
+                        Err(_) => Err(anyhow::anyhow!("timed out")),
+                    };
+                    if sender.send((address, result)).is_err() {
+                        // another client has already been accepted
+                    }
+                });
+            }
+            Some((address, result)) = handshakes.recv() => match result {
+                Ok(transport) => {
+                    eprintln!("SUCCESS: {:?}{}", address, encrypted_note(&transport));
+                    return Ok(transport);
+                }
+                Err(error) => {
+                    eprint!("rejected {:?} ({}) ... ", address, error);
+                    io::stderr().flush()?;
+                }
+            },
+        }
+    }
+}
+
+async fn handshake_with_client(
+    mut socket: TcpStream,
+    password: Option<String>,
+    key: Option<String>,
+) -> Result<Transport> {
+    if !auth::accept_client(&mut socket, password.as_deref()).await? {
+        bail!("wrong password");
+    }
+    Ok(Transport::host(socket, key.as_deref()).await?)
+}
+
+pub async fn connect(
+    address: &str,
+    password: Option<&str>,
+    key: Option<&str>,
+) -> Result<Transport> {
+    let socket = with_timeout(CONNECT_TIMEOUT, TcpStream::connect(address)).await?;
+    let peer = socket.peer_addr()?;
+    // A host that accepts the connection but never answers would otherwise hang the client
+    let handshake = handshake_with_host(socket, password, key);
+    let transport = match tokio::time::timeout(CONNECT_TIMEOUT, handshake).await {
+        Ok(result) => result?,
+        Err(_) => bail!("The host didn't complete the handshake in time"),
+    };
+    eprintln!("SUCCESS: {:?}{}", peer, encrypted_note(&transport));
+    Ok(transport)
+}
+
+async fn handshake_with_host(
+    mut socket: TcpStream,
+    password: Option<&str>,
+    key: Option<&str>,
+) -> Result<Transport> {
+    match auth::authenticate(&mut socket, password).await? {
+        AuthOutcome::Accepted => {}
+        AuthOutcome::Rejected => bail!("The host rejected the password"),
+        AuthOutcome::PasswordRequired => {
+            bail!("The host requires a password (set --password or ACHTUNG_PASSWORD)")
+        }
+    }
+    match Transport::join(socket, key).await {
+        Ok(transport) => Ok(transport),
+        Err(error) => bail!("{} (the key is set with --key or ACHTUNG_KEY)", error),
+    }
+}
+
+fn encrypted_note(transport: &Transport) -> &'static str {
+    if transport.is_encrypted() {
+        " (encrypted)"
+    } else {
+        ""
+    }
+}
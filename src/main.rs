@@ -1,74 +1,268 @@
-mod app;
-mod game;
-mod headless;
-mod net;
-mod user_interface;
+mod cli;
 
-use std::io::{self, Write};
-use std::net::{TcpListener, TcpStream};
-use std::{env, panic};
-
-use anyhow::Result;
-use app::App;
+use std::thread;
+use std::time::Duration;
 
-use crate::app::GameMode;
+use achtung_rs::ai::AiKind;
+#[cfg(feature = "tui")]
+use achtung_rs::app::{App, GameMode};
+use achtung_rs::config::{self, Settings};
+#[cfg(feature = "tui")]
+use achtung_rs::events::{EventLog, EventTarget};
+use achtung_rs::headless::{Driver, Headless};
+use achtung_rs::lobby;
+#[cfg(feature = "tui")]
+use achtung_rs::replay::Replay;
+#[cfg(feature = "tui")]
+use achtung_rs::stats::StatsRecorder;
+use achtung_rs::stats::{self, Leaderboard, StatsStore};
+use achtung_rs::tournament::Tournament;
+#[cfg(feature = "tui")]
+use anyhow::Context;
+use anyhow::{bail, Result};
+use clap::{CommandFactory, Parser};
+#[cfg(feature = "tui")]
+use cli::OfflineArgs;
+use cli::{Cli, Command, ConfigCommand, HeadlessPlayerArgs, HeadlessRole};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use tokio::net::TcpListener;
 
-const DEFAULT_PORT: u32 = 8000;
-pub type Point = (i32, i32);
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let mut settings = Settings::load(cli.config.as_deref(), cli.profile.as_deref())?;
+    let command = match cli.command {
+        Some(command) => command,
+        #[cfg(feature = "tui")]
+        None => Command::Offline(OfflineArgs::default()),
+        #[cfg(not(feature = "tui"))]
+        None => Cli::command()
+            .error(
+                clap::error::ErrorKind::MissingSubcommand,
+                "This build has no terminal UI, so a command is needed",
+            )
+            .exit(),
+    };
 
-fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let mode = match args.get(1).map(|s| &s[..]) {
-        Some("host") => {
-            let address = args
-                .get(2)
-                .map(String::to_string)
-                .unwrap_or_else(|| format!("localhost:{}", DEFAULT_PORT));
-            let listener = TcpListener::bind(address)?;
+    match command {
+        #[cfg(feature = "tui")]
+        Command::Offline(args) => {
+            let events = open_event_log(args.events.as_ref())?;
+            let stats = stats_store(cli.stats)
+                .map(StatsRecorder::open)
+                .transpose()?;
+            let seed = args.seed;
+            args.apply(&mut settings);
+            exit_if_invalid(settings.validate());
+            play(GameMode::Offline(seed), &settings, events, stats).await?;
+        }
+        #[cfg(feature = "tui")]
+        Command::Host(args) => {
+            let events = open_event_log(args.events.as_ref())?;
+            let stats = stats_store(cli.stats)
+                .map(StatsRecorder::open)
+                .transpose()?;
+            args.game.apply(&mut settings);
+            exit_if_invalid(settings.validate_online());
+            let name = args.name.or(settings.name.clone()).unwrap_or("Host".into());
+            let listener = TcpListener::bind(&args.bind).await?;
             let local_addr = listener.local_addr()?;
-            print!("Waiting for client ({:?}) ... ", local_addr);
-            io::stdout().flush()?;
-            let (socket, address) = listener.accept()?;
-            println!("SUCCESS: {:?}", address);
-            let name = args
-                .get(3)
-                .map(String::to_string)
-                .unwrap_or_else(|| "Host".to_string());
-            GameMode::Host(socket, name)
+            eprint!("Waiting for client ({:?}) ... ", local_addr);
+            io::stderr().flush()?;
+            let socket =
+                lobby::accept_client(&listener, args.connection.password, args.connection.key)
+                    .await?;
+            play(GameMode::Host(socket, name), &settings, events, stats).await?;
         }
-        Some("client") => {
-            let address = args
-                .get(2)
-                .map(String::to_string)
-                .unwrap_or_else(|| format!("localhost:{}", DEFAULT_PORT));
-            print!("Connecting to host on {:?} ... ", address);
-            io::stdout().flush()?;
-            let socket = TcpStream::connect(address)?;
-            println!("SUCCESS: {:?}", socket);
+        #[cfg(feature = "tui")]
+        Command::Client(args) => {
+            let events = open_event_log(args.events.as_ref())?;
+            let stats = stats_store(cli.stats)
+                .map(StatsRecorder::open)
+                .transpose()?;
+            if let Some(tick) = args.tick {
+                settings.tick_duration = tick;
+            }
+            exit_if_invalid(settings.validate_online());
             let name = args
-                .get(3)
-                .map(String::to_string)
-                .unwrap_or_else(|| "Client".to_string());
-            GameMode::Client(socket, name)
+                .name
+                .or(settings.name.clone())
+                .unwrap_or("Client".into());
+            eprint!("Connecting to host on {:?} ... ", args.address);
+            io::stderr().flush()?;
+            let connection = &args.connection;
+            let socket = lobby::connect(
+                &args.address,
+                connection.password.as_deref(),
+                connection.key.as_deref(),
+            )
+            .await?;
+            play(GameMode::Client(socket, name), &settings, events, stats).await?;
         }
-        Some("headless") => {
-            let address = args
-                .get(2)
-                .map(String::to_string)
-                .unwrap_or_else(|| format!("localhost:{}", DEFAULT_PORT));
-            print!("Connecting to host on {:?} ... ", address);
-            io::stdout().flush()?;
-            let socket = TcpStream::connect(address)?;
-            println!("SUCCESS: {:?}", socket);
-            headless::run(socket);
-            return Ok(());
+        Command::Headless(args) => match args.role.unwrap_or(HeadlessRole::Join(args.join)) {
+            HeadlessRole::Join(args) => {
+                let connection = &args.player.connection;
+                let (password, key) = (connection.password.clone(), connection.key.clone());
+                let headless = headless_player(&args.player, "Headless client", &settings)?;
+                eprint!("Connecting to host on {:?} ... ", args.address);
+                io::stderr().flush()?;
+                let socket =
+                    lobby::connect(&args.address, password.as_deref(), key.as_deref()).await?;
+                headless.join(socket).await?;
+            }
+            HeadlessRole::Host(args) => {
+                let connection = &args.player.connection;
+                let (password, key) = (connection.password.clone(), connection.key.clone());
+                let rules = args.rules(&settings);
+                let headless = headless_player(&args.player, "Headless host", &settings)?;
+                let listener = TcpListener::bind(&args.address).await?;
+                eprint!("Waiting for client ({:?}) ... ", listener.local_addr()?);
+                io::stderr().flush()?;
+                let socket = lobby::accept_client(&listener, password, key).await?;
+                headless.host(socket, rules).await?;
+            }
+        },
+        Command::Server(mut args) => {
+            if args.player.script.is_none() && args.player.ai.is_none() {
+                args.player.ai = Some(AiKind::default());
+            }
+            let connection = &args.player.connection;
+            let rules = args.rules(&settings);
+            let listener = TcpListener::bind(&args.address).await?;
+            // A client that leaves or can't be played with doesn't stop the server
+            loop {
+                eprint!("Waiting for client ({:?}) ... ", listener.local_addr()?);
+                io::stderr().flush()?;
+                let socket = lobby::accept_client(
+                    &listener,
+                    connection.password.clone(),
+                    connection.key.clone(),
+                )
+                .await?;
+                let headless = headless_player(&args.player, "Headless host", &settings)?;
+                if let Err(error) = headless.host(socket, rules).await {
+                    eprintln!("The game failed: {:#}", error);
+                }
+            }
         }
-        Some(other) => panic!("Invalid game mode: {}", other),
-        None => GameMode::Offline,
+        #[cfg(feature = "tui")]
+        Command::Replay(args) => {
+            let replay = Replay::load(&args.log, args.round.map(|round| round as usize))?;
+            replay
+                .show(args.tick.unwrap_or(settings.tick_duration))
+                .await?;
+        }
+        Command::Tournament(args) => {
+            let size = args.size.unwrap_or(settings.game_size);
+            let mut tournament = match Tournament::new(args.strategies, args.games, size) {
+                Ok(tournament) => tournament,
+                Err(message) => Cli::command()
+                    .error(clap::error::ErrorKind::ValueValidation, message)
+                    .exit(),
+            };
+            tournament.think_time = Duration::from_millis(args.think_ms);
+            tournament.threads = args.threads.map(usize::from).unwrap_or_else(|| {
+                thread::available_parallelism().map_or(1, |threads| threads.get())
+            });
+            tournament.seed = args.seed;
+            let standings = tournament.run();
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&standings)?);
+            } else {
+                print!("{}", standings);
+            }
+        }
+        Command::Stats(args) => {
+            let store = match stats_store(cli.stats) {
+                Some(store) => store,
+                None => bail!("Couldn't find the user's data dir"),
+            };
+            let leaderboard = Leaderboard::new(&store.load()?, &args.names);
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&leaderboard)?);
+            } else {
+                print!("{}", leaderboard);
+            }
+        }
+        Command::Config(ConfigCommand::Dump(args)) => {
+            args.apply(&mut settings);
+            print!("{}", settings.dump());
+        }
+        Command::Config(ConfigCommand::Path) => match cli.config.or_else(config::default_path) {
+            Some(path) => println!("{}", path.display()),
+            None => bail!("Couldn't find the user's config dir"),
+        },
+    }
+
+    Ok(())
+}
+
+fn headless_player(
+    args: &HeadlessPlayerArgs,
+    default_name: &str,
+    settings: &Settings,
+) -> Result<Headless> {
+    let tick = args.tick.unwrap_or(settings.tick_duration);
+    let driver = match (&args.script, args.ai, args.seed) {
+        (Some(script), _, _) => Driver::script(script)?,
+        (None, Some(strategy), Some(seed)) => Driver::Ai(strategy.create_seeded(tick / 3, seed)),
+        (None, Some(strategy), None) => Driver::Ai(strategy.create(tick / 3)),
+        (None, None, _) => Driver::Stdin,
     };
+    Ok(Headless {
+        name: args
+            .name
+            .clone()
+            .or(settings.name.clone())
+            .unwrap_or(default_name.into()),
+        driver,
+        tick,
+        json: args.json,
+    })
+}
+
+fn stats_store(path: Option<PathBuf>) -> Option<StatsStore> {
+    path.or_else(stats::default_path).map(StatsStore::new)
+}
 
-    let mut app = App::new(mode).expect("Creating app");
-    app.run().expect("Running app");
+// Opened before anything else happens, so that a bad target is reported right away
+#[cfg(feature = "tui")]
+fn open_event_log(target: Option<&EventTarget>) -> Result<Option<EventLog>> {
+    target
+        .map(|target| {
+            EventLog::open(target)
+                .with_context(|| format!("Failed to open event stream {}", target))
+        })
+        .transpose()
+}
 
-    Ok(())
+#[cfg(feature = "tui")]
+async fn play(
+    mode: GameMode,
+    settings: &Settings,
+    events: Option<EventLog>,
+    stats: Option<StatsRecorder>,
+) -> Result<()> {
+    let mut app = App::new(mode, settings)
+        .await
+        .context("Failed to start the game")?;
+    if let Some(log) = events {
+        app.stream_events(log)
+            .context("Failed to write to the event stream")?;
+    }
+    if let Some(stats) = stats {
+        app.record_stats(stats);
+    }
+    app.run().await
+}
+
+// Settings that the game can't be played with are reported like bad arguments
+#[cfg(feature = "tui")]
+fn exit_if_invalid(validation: std::result::Result<(), String>) {
+    if let Err(message) = validation {
+        Cli::command()
+            .error(clap::error::ErrorKind::ValueValidation, message)
+            .exit();
+    }
 }
@@ -1,160 +1,376 @@
-use crate::app::ThreadMessage;
-use crate::game::{Direction, PlayerIndex, DOWN, LEFT, RIGHT, UP};
-use std::io::{ErrorKind, Read, Write};
-use std::net::TcpStream;
-use std::sync::mpsc::Sender;
-use std::sync::{Arc, Mutex};
-use std::thread;
+use crate::game::{Direction, PlayerIndex, TieRule, DOWN, LEFT, RIGHT, UP};
+use crate::transport::{Traffic, Transport, TransportReader, TransportWriter};
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+// How long the remote gets to complete the handshake that precedes the game
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct Networking {
-    socket: TcpStream,
-    session: Arc<Mutex<Session>>,
+    outgoing: UnboundedSender<Vec<u8>>,
+    socket_writer: JoinHandle<()>,
+    events: Option<UnboundedReceiver<NetworkEvent>>,
+    session: Session,
+    traffic: Traffic,
 }
 
 impl Networking {
-    pub fn host(
-        mut socket: TcpStream,
+    pub async fn host(
+        mut socket: Transport,
         local_player: PlayerIndex,
         remote_player: PlayerIndex,
         player_direction: Direction,
         frame: u32,
-        game_size: (u16, u16),
+        rules: Rules,
         local_player_name: String,
-    ) -> (Self, GameInfo) {
-        ChooseGameSizePacket(game_size).write(&mut socket);
-        ChooseNamePacket(local_player_name).write(&mut socket);
-
-        let remote_player_name = ChooseNamePacket::read(&mut socket).0;
+    ) -> NetResult<(Self, GameInfo)> {
+        let size_packet = ChooseGameSizePacket(rules.size);
+        let tie_rule_packet = ChooseTieRulePacket(rules.tie_rule);
+        let power_ups_packet = ChoosePowerUpsPacket(rules.power_ups);
+        let name_packet = ChooseNamePacket(local_player_name);
+        let remote_name_packet = with_timeout(HANDSHAKE_TIMEOUT, async {
+            size_packet.write(&mut socket).await?;
+            tie_rule_packet.write(&mut socket).await?;
+            power_ups_packet.write(&mut socket).await?;
+            name_packet.write(&mut socket).await?;
+            ChooseNamePacket::read(&mut socket).await
+        })
+        .await?;
+
+        let session = Session::new(local_player, remote_player, player_direction, frame);
 
         let game_info = GameInfo {
-            size: game_size,
-            remote_player_name,
+            rules,
+            remote_player_name: remote_name_packet.0,
         };
 
-        let session = Arc::new(Mutex::new(Session::new(
-            local_player,
-            remote_player,
-            player_direction,
-            frame,
-        )));
-
-        (Self { socket, session }, game_info)
+        Ok((Self::new(socket, session), game_info))
     }
 
-    pub fn join(
-        mut socket: TcpStream,
+    pub async fn join(
+        mut socket: Transport,
         local_player: PlayerIndex,
         remote_player: PlayerIndex,
         player_direction: Direction,
         frame: u32,
         local_player_name: String,
-    ) -> (Self, GameInfo) {
-        ChooseNamePacket(local_player_name).write(&mut socket);
-
-        let game_size = ChooseGameSizePacket::read(&mut socket).0;
-        let remote_player_name = ChooseNamePacket::read(&mut socket).0;
+    ) -> NetResult<(Self, GameInfo)> {
+        let name_packet = ChooseNamePacket(local_player_name);
+        let (size_packet, tie_rule_packet, power_ups_packet, remote_name_packet) =
+            with_timeout(HANDSHAKE_TIMEOUT, async {
+                name_packet.write(&mut socket).await?;
+                let size_packet = ChooseGameSizePacket::read(&mut socket).await?;
+                let tie_rule_packet = ChooseTieRulePacket::read(&mut socket).await?;
+                let power_ups_packet = ChoosePowerUpsPacket::read(&mut socket).await?;
+                let remote_name_packet = ChooseNamePacket::read(&mut socket).await?;
+                Ok((
+                    size_packet,
+                    tie_rule_packet,
+                    power_ups_packet,
+                    remote_name_packet,
+                ))
+            })
+            .await?;
+
+        let session = Session::new(local_player, remote_player, player_direction, frame);
 
         let game_info = GameInfo {
-            size: game_size,
-            remote_player_name,
+            rules: Rules {
+                size: size_packet.0,
+                tie_rule: tie_rule_packet.0,
+                power_ups: power_ups_packet.0,
+            },
+            remote_player_name: remote_name_packet.0,
         };
 
-        let session = Arc::new(Mutex::new(Session::new(
-            local_player,
-            remote_player,
-            player_direction,
-            frame,
-        )));
+        Ok((Self::new(socket, session), game_info))
+    }
 
-        (Self { socket, session }, game_info)
+    fn new(socket: Transport, session: Session) -> Self {
+        let traffic = socket.traffic();
+        let (reader, writer) = socket.into_split();
+        let (event_sender, events) = mpsc::unbounded_channel();
+        let (outgoing, outgoing_receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run_socket_reader(reader, event_sender.clone()));
+        let socket_writer =
+            tokio::spawn(run_socket_writer(writer, outgoing_receiver, event_sender));
+        Self {
+            outgoing,
+            socket_writer,
+            events: Some(events),
+            session,
+            traffic,
+        }
     }
 
-    pub fn start_game(&mut self, sender: Sender<ThreadMessage>) -> NetResult<Vec<Outcome>> {
-        self.spawn_socket_reader(sender)?;
+    /// The returned events should be passed to `handle_event` as they arrive
+    pub fn start_game(&mut self) -> (UnboundedReceiver<NetworkEvent>, Vec<Outcome>) {
+        let events = self
+            .events
+            .take()
+            .expect("The game has already been started");
 
-        let outgoing_packet = self.session.lock().unwrap().start_game();
+        let outgoing_packet = self.session.start_game();
         let mut outcomes = vec![];
-        self.send_packet(outgoing_packet.0, &mut outcomes)?;
-        Ok(outcomes)
+        self.send_packet(outgoing_packet.0, &mut outcomes);
+        (events, outcomes)
+    }
+
+    pub fn local_player_index(&self) -> PlayerIndex {
+        self.session.player
     }
 
     pub fn remote_player_index(&self) -> PlayerIndex {
-        self.session.lock().unwrap().remote_player
+        self.session.remote_player
+    }
+
+    /// `player_direction` is where the local player is heading in the game. It's sent along with
+    /// the frame, rather than the last direction that was set, which the game may have turned down.
+    pub fn start_new_frame(&mut self, frame: u32, player_direction: Direction) -> Vec<Outcome> {
+        let (outgoing_packets, mut outcomes) =
+            self.session.start_new_frame(frame, player_direction);
+        for outgoing_packet in outgoing_packets {
+            self.send_packet(outgoing_packet.0, &mut outcomes);
+        }
+        outcomes
     }
 
-    pub fn start_new_frame(&mut self, frame: u32) -> NetResult<Vec<Outcome>> {
-        let (outgoing_packet, mut outcomes) = self.session.lock().unwrap().start_new_frame(frame);
-        self.send_packet(outgoing_packet.0, &mut outcomes)?;
-        Ok(outcomes)
+    pub fn set_direction(&mut self, direction: Direction) -> Vec<Outcome> {
+        let (outgoing_packet, mut outcomes) = self.session.set_direction(direction);
+        if let Some(outgoing_packet) = outgoing_packet {
+            self.send_packet(outgoing_packet.0, &mut outcomes);
+        }
+        outcomes
     }
 
-    pub fn set_direction(&mut self, direction: Direction) -> NetResult<Vec<Outcome>> {
-        let (outgoing_packet, mut outcomes) = self.session.lock().unwrap().set_direction(direction);
+    /// Boosts the local player, in the current frame if it's still open and else in the next one
+    pub fn boost(&mut self) -> Vec<Outcome> {
+        let (outgoing_packet, mut outcomes) = self.session.boost();
         if let Some(outgoing_packet) = outgoing_packet {
-            self.send_packet(outgoing_packet.0, &mut outcomes)?;
+            self.send_packet(outgoing_packet.0, &mut outcomes);
         }
-        Ok(outcomes)
+        outcomes
+    }
+
+    /// Whether a direction set now would still make it into the current frame, rather than
+    /// being held back until the remote has committed it too
+    pub fn is_frame_open(&self) -> bool {
+        !self.session.has_committed_frame
     }
 
-    pub fn commit_frame(&mut self) -> NetResult<Vec<Outcome>> {
-        let (outgoing_packet, mut outcomes) = self.session.lock().unwrap().commit_frame();
+    /// Called on every tick. A frame that is still waiting on the remote by the next tick counts
+    /// as a stall in the stats.
+    pub fn commit_frame(&mut self) -> Vec<Outcome> {
+        let (outgoing_packet, mut outcomes) = self.session.commit_frame();
         if let Some(outgoing_packet) = outgoing_packet {
-            self.send_packet(outgoing_packet.0, &mut outcomes)?;
+            self.send_packet(outgoing_packet.0, &mut outcomes);
+
+            // One ping per frame is enough to keep the RTT estimate fresh
+            let ping = self.session.ping();
+            self.send_packet(ping.0, &mut outcomes);
         }
-        Ok(outcomes)
+        outcomes
     }
 
-    pub fn take_buffered_outcomes(&mut self) -> Vec<Outcome> {
-        let mut session = self.session.lock().unwrap();
-        std::mem::take(&mut session.buffered_outcomes)
+    /// Asks for a rematch, once the round is over. Asking again before the remote has answered
+    /// does nothing.
+    pub fn request_rematch(&mut self, swap_sides: bool) -> Vec<Outcome> {
+        let (outgoing_packet, mut outcomes) = self.session.request_rematch(swap_sides);
+        if let Some(outgoing_packet) = outgoing_packet {
+            self.send_packet(outgoing_packet.0, &mut outcomes);
+        }
+        outcomes
     }
 
-    pub fn exit(&mut self) {
+    /// Called when the game is over, after which rematches can be asked for
+    pub fn end_round(&mut self) {
+        self.session.round_over = true;
+    }
+
+    /// Called once the game for an agreed upon rematch (see `Outcome::Rematch`) has been set up
+    pub fn restart_game(&mut self, player_direction: Direction) -> Vec<Outcome> {
+        let outgoing_packet = self.session.restart_game(player_direction);
+        let mut outcomes = vec![];
+        self.send_packet(outgoing_packet.0, &mut outcomes);
+        outcomes
+    }
+
+    pub fn send_chat(&mut self, text: &str) -> Vec<Outcome> {
         let mut outcomes = vec![];
-        match self.send_packet(SessionPacket::GoodBye, &mut outcomes) {
-            Ok(()) => {}
-            Err(error) => panic!("Failed to send goodbye: {:?}", error),
+        let bytes = ChatPacket(text.to_string()).serialize();
+        self.send_bytes(bytes, &mut outcomes);
+        outcomes
+    }
+
+    pub fn stats(&self) -> NetStats {
+        NetStats {
+            bytes_sent: self.traffic.sent(),
+            bytes_received: self.traffic.received(),
+            ..self.session.stats.clone()
         }
     }
 
-    fn send_packet(&mut self, packet: SessionPacket, outcomes: &mut Vec<Outcome>) -> NetResult<()> {
-        if let Err(io_error) = self.socket.write_all(&[packet.serialize()]) {
-            match io_error.kind() {
-                ErrorKind::ConnectionReset | ErrorKind::BrokenPipe => {
-                    outcomes.push(Outcome::RemoteLeft { politely: false })
+    pub fn handle_event(&mut self, event: NetworkEvent) -> NetResult<Vec<Outcome>> {
+        match event {
+            NetworkEvent::Received(ReceivedPacket(packet)) => match packet {
+                IncomingPacket::Session(SessionPacket::SetDirection(pkt)) => {
+                    self.session.on_received_set_direction(pkt)?
                 }
-                _ => return Err(io_error),
-            }
+                IncomingPacket::Session(SessionPacket::Boost(frame_modulo)) => {
+                    self.session.on_received_boost(frame_modulo)?
+                }
+                IncomingPacket::Session(SessionPacket::CommitFrame(pkt)) => {
+                    self.session.on_received_commit_frame(pkt)?
+                }
+                IncomingPacket::Session(SessionPacket::Ping(sequence)) => {
+                    let mut outcomes = vec![];
+                    self.send_packet(SessionPacket::Pong(sequence), &mut outcomes);
+                    self.session.buffered_outcomes.extend(outcomes);
+                }
+                IncomingPacket::Session(SessionPacket::Pong(sequence)) => {
+                    self.session.on_received_pong(sequence)
+                }
+                IncomingPacket::Session(SessionPacket::Rematch { swap_sides }) => {
+                    self.session.on_received_rematch(swap_sides)
+                }
+                IncomingPacket::Session(SessionPacket::GoodBye) => {
+                    self.session.on_received_good_bye()
+                }
+                IncomingPacket::Chat(chat) => self.session.on_received_chat(chat),
+            },
+            NetworkEvent::Disconnected => self
+                .session
+                .buffered_outcomes
+                .push(Outcome::RemoteLeft { politely: false }),
+            NetworkEvent::Error(error) => return Err(Error::other(error)),
         }
+        Ok(std::mem::take(&mut self.session.buffered_outcomes))
+    }
 
-        Ok(())
+    /// Says goodbye and waits until everything that has been sent is written to the socket
+    pub async fn exit(mut self) {
+        let mut outcomes = vec![];
+        self.send_packet(SessionPacket::GoodBye, &mut outcomes);
+        drop(self.outgoing);
+        if let Err(error) = self.socket_writer.await {
+            panic!("Failed to send goodbye: {:?}", error);
+        }
     }
 
-    pub fn spawn_socket_reader(&mut self, sender: Sender<ThreadMessage>) -> NetResult<()> {
-        let socket = self.socket.try_clone()?;
-        let session = Arc::clone(&self.session);
-        thread::spawn(move || run_socket_reader(socket, sender, session));
-        Ok(())
+    fn send_packet(&mut self, packet: SessionPacket, outcomes: &mut Vec<Outcome>) {
+        self.send_bytes(vec![packet.serialize()], outcomes)
+    }
+
+    fn send_bytes(&mut self, bytes: Vec<u8>, outcomes: &mut Vec<Outcome>) {
+        if self.outgoing.send(bytes).is_err() {
+            // The writer has stopped because the connection was lost
+            outcomes.push(Outcome::RemoteLeft { politely: false });
+        }
     }
 }
 
+pub async fn with_timeout<T>(
+    duration: Duration,
+    future: impl Future<Output = NetResult<T>>,
+) -> NetResult<T> {
+    match tokio::time::timeout(duration, future).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::new(ErrorKind::TimedOut, "Timed out")),
+    }
+}
+
+fn out_of_sync(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// What the host decides for both players
+#[derive(Debug, Copy, Clone)]
+pub struct Rules {
+    pub size: (u16, u16),
+    pub tie_rule: TieRule,
+    pub power_ups: bool,
+}
+
 #[derive(Debug)]
 pub struct GameInfo {
-    pub size: (u16, u16),
+    pub rules: Rules,
     pub remote_player_name: String,
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct NetStats {
+    pub rtt: Option<Duration>,
+    pub jitter: Duration,
+    /// Counted on the socket, so encryption overhead is included
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Frames that were still waiting on the remote's commit when the next tick came, rather
+    /// than every frame that the local player happened to commit first
+    pub frames_waited_on_remote: u32,
+    /// The longest that one of those frames waited, from the local commit to the remote's
+    pub longest_stall: Duration,
+}
+
+impl NetStats {
+    fn record_rtt(&mut self, rtt: Duration) {
+        if let Some(previous) = self.rtt {
+            let delta = rtt.abs_diff(previous);
+            // Smoothed the same way as the interarrival jitter in RFC 3550
+            self.jitter = self.jitter.mul_f64(15.0 / 16.0) + delta / 16;
+        }
+        self.rtt = Some(rtt);
+    }
+
+    fn record_stall(&mut self, stall: Duration) {
+        self.longest_stall = self.longest_stall.max(stall);
+    }
+}
+
+impl std::fmt::Display for NetStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.rtt {
+            Some(rtt) => write!(f, "rtt={:.1}ms", rtt.as_secs_f64() * 1000.0)?,
+            None => write!(f, "rtt=?")?,
+        }
+        write!(
+            f,
+            " jitter={:.1}ms sent={}B received={}B waited={} frames longest_stall={}ms",
+            self.jitter.as_secs_f64() * 1000.0,
+            self.bytes_sent,
+            self.bytes_received,
+            self.frames_waited_on_remote,
+            self.longest_stall.as_millis()
+        )
+    }
+}
+
 struct Session {
     player: PlayerIndex,
     remote_player: PlayerIndex,
     player_direction: Direction,
+    first_frame: u32,
     frame: u32,
     queued_command_from_remote: Option<Direction>,
     has_remote_committed_frame: bool,
     has_remote_committed_next_frame: bool,
     has_committed_frame: bool,
     queued_command: Option<Direction>,
+    queued_boost: bool,
+    queued_boost_from_remote: bool,
     buffered_outcomes: Vec<Outcome>,
+    waiting_on_remote_since: Option<Instant>,
+    // Whether the current frame has missed a tick waiting on the remote
+    stalled: bool,
+    next_ping: u8,
+    pending_pings: [Option<Instant>; 32],
+    stats: NetStats,
+    // Rematches are only asked for, and listened to, between rounds
+    round_over: bool,
+    rematch_request: Option<bool>,
+    remote_rematch_request: Option<bool>,
 }
 
 impl Session {
@@ -168,13 +384,24 @@ impl Session {
             player: local_player,
             remote_player,
             player_direction,
+            first_frame: frame,
             frame,
             queued_command_from_remote: None,
             has_remote_committed_frame: false,
             has_remote_committed_next_frame: false,
             has_committed_frame: false,
             queued_command: None,
+            queued_boost: false,
+            queued_boost_from_remote: false,
             buffered_outcomes: Vec::new(),
+            waiting_on_remote_since: None,
+            stalled: false,
+            next_ping: 0,
+            pending_pings: [None; 32],
+            stats: NetStats::default(),
+            round_over: false,
+            rematch_request: None,
+            remote_rematch_request: None,
         }
     }
 
@@ -185,10 +412,75 @@ impl Session {
         )))
     }
 
-    fn start_new_frame(&mut self, frame: u32) -> (OutgoingPacket, Vec<Outcome>) {
+    fn restart_game(&mut self, player_direction: Direction) -> OutgoingPacket {
+        self.player_direction = player_direction;
+        self.start_game()
+    }
+
+    fn request_rematch(&mut self, swap_sides: bool) -> (Option<OutgoingPacket>, Vec<Outcome>) {
+        if !self.round_over || self.rematch_request.is_some() {
+            return (None, vec![]);
+        }
+        self.rematch_request = Some(swap_sides);
+        if let Some(remote_swap_sides) = self.remote_rematch_request {
+            self.agree_on_rematch(swap_sides || remote_swap_sides);
+        }
+        let outgoing_packet = OutgoingPacket(SessionPacket::Rematch { swap_sides });
+        (
+            Some(outgoing_packet),
+            std::mem::take(&mut self.buffered_outcomes),
+        )
+    }
+
+    // A rematch packet that arrives in the middle of a round is a late repeat of one that has
+    // already been agreed on
+    fn on_received_rematch(&mut self, swap_sides: bool) {
+        if !self.round_over {
+            return;
+        }
+        self.remote_rematch_request = Some(swap_sides);
+        match self.rematch_request {
+            Some(local_swap_sides) => self.agree_on_rematch(swap_sides || local_swap_sides),
+            None => self
+                .buffered_outcomes
+                .push(Outcome::RemoteWantsRematch { swap_sides }),
+        }
+    }
+
+    // Both peers have asked for a rematch. The frame state is reset right away, so that the
+    // remote's packets for the new game, which may follow right behind its rematch packet, are
+    // matched against the right frame.
+    fn agree_on_rematch(&mut self, swap_sides: bool) {
+        self.frame = self.first_frame;
+        self.queued_command_from_remote = None;
+        self.has_remote_committed_frame = false;
+        self.has_remote_committed_next_frame = false;
+        self.has_committed_frame = false;
+        self.queued_command = None;
+        self.queued_boost = false;
+        self.queued_boost_from_remote = false;
+        self.waiting_on_remote_since = None;
+        self.stalled = false;
+        self.round_over = false;
+        self.rematch_request = None;
+        self.remote_rematch_request = None;
+        self.buffered_outcomes.push(Outcome::Rematch {
+            swap_sides,
+            frame: self.first_frame,
+        });
+    }
+
+    fn start_new_frame(
+        &mut self,
+        frame: u32,
+        player_direction: Direction,
+    ) -> (Vec<OutgoingPacket>, Vec<Outcome>) {
         self.frame = frame;
+        self.player_direction = player_direction;
         self.has_committed_frame = false;
         self.has_remote_committed_frame = false;
+        self.waiting_on_remote_since = None;
+        self.stalled = false;
 
         if let Some(dir) = self.queued_command.take() {
             self.player_direction = dir;
@@ -207,18 +499,67 @@ impl Session {
                 )));
         }
 
+        if std::mem::take(&mut self.queued_boost_from_remote) {
+            self.buffered_outcomes
+                .push(Outcome::PlayerBoost(self.remote_player));
+        }
+
         if self.has_remote_committed_next_frame {
             self.has_remote_committed_frame = true;
             self.has_remote_committed_next_frame = false;
         }
 
-        let outgoing_packet = OutgoingPacket(SessionPacket::SetDirection(SetDirectionPacket::new(
-            self.frame,
-            self.player_direction,
-        )));
+        let mut outgoing_packets = vec![OutgoingPacket(SessionPacket::SetDirection(
+            SetDirectionPacket::new(self.frame, self.player_direction),
+        ))];
+        if std::mem::take(&mut self.queued_boost) {
+            self.buffered_outcomes
+                .push(Outcome::PlayerBoost(self.player));
+            outgoing_packets.push(OutgoingPacket(SessionPacket::Boost(
+                SessionPacket::boost_modulo(self.frame),
+            )));
+        }
+        (
+            outgoing_packets,
+            std::mem::take(&mut self.buffered_outcomes),
+        )
+    }
+
+    fn boost(&mut self) -> (Option<OutgoingPacket>, Vec<Outcome>) {
+        let outgoing_packet = if self.has_committed_frame {
+            self.queued_boost = true;
+            None
+        } else {
+            self.buffered_outcomes
+                .push(Outcome::PlayerBoost(self.player));
+            Some(OutgoingPacket(SessionPacket::Boost(
+                SessionPacket::boost_modulo(self.frame),
+            )))
+        };
         (outgoing_packet, std::mem::take(&mut self.buffered_outcomes))
     }
 
+    // A remote that sends something for a frame that it has already committed, or for one that
+    // isn't the current or the next, is out of sync, and the game can't go on with it
+    fn on_received_boost(&mut self, frame_modulo: u8) -> NetResult<()> {
+        if frame_modulo == SessionPacket::boost_modulo(self.frame)
+            && !self.has_remote_committed_frame
+        {
+            self.buffered_outcomes
+                .push(Outcome::PlayerBoost(self.remote_player));
+        } else if frame_modulo == SessionPacket::boost_modulo(self.frame + 1)
+            && !self.has_remote_committed_next_frame
+        {
+            self.queued_boost_from_remote = true;
+        } else {
+            return Err(out_of_sync(format!(
+                "Received boost with unexpected frame modulo: {}. Our frame: {}",
+                frame_modulo, self.frame
+            )));
+        }
+        Ok(())
+    }
+
     fn set_direction(&mut self, direction: Direction) -> (Option<OutgoingPacket>, Vec<Outcome>) {
         let outgoing_packet = if self.has_committed_frame {
             self.queued_command = Some(direction);
@@ -244,59 +585,89 @@ impl Session {
 
             if self.has_remote_committed_frame {
                 self.buffered_outcomes.push(Outcome::RunFrame);
+            } else {
+                self.waiting_on_remote_since = Some(Instant::now());
             }
             let outgoing_packet = OutgoingPacket(SessionPacket::CommitFrame(
                 CommitFramePacket::new(self.frame),
             ));
             Some(outgoing_packet)
         } else {
+            // Committed on an earlier tick and still waiting, so the game is held up
+            if self.waiting_on_remote_since.is_some() && !self.stalled {
+                self.stalled = true;
+                self.stats.frames_waited_on_remote += 1;
+            }
             None
         };
         (outgoing_packet, std::mem::take(&mut self.buffered_outcomes))
     }
 
-    fn on_received_set_direction(&mut self, pkt: SetDirectionPacket) -> bool {
-        if pkt.frame_modulo == SessionPacket::modulo(self.frame) {
-            assert!(!self.has_remote_committed_frame);
-
+    fn on_received_set_direction(&mut self, pkt: SetDirectionPacket) -> NetResult<()> {
+        if pkt.frame_modulo == SessionPacket::modulo(self.frame) && !self.has_remote_committed_frame
+        {
             self.buffered_outcomes
                 .push(Outcome::PlayerControl(PlayerControlOutcome::new(
                     self.remote_player,
                     pkt.direction,
                 )));
-            true
-        } else if pkt.frame_modulo == SessionPacket::modulo(self.frame + 1) {
-            assert!(!self.has_remote_committed_next_frame);
+        } else if pkt.frame_modulo == SessionPacket::modulo(self.frame + 1)
+            && !self.has_remote_committed_next_frame
+        {
             self.queued_command_from_remote = Some(pkt.direction);
-            false
         } else {
-            panic!(
+            return Err(out_of_sync(format!(
                 "Received command with unexpected frame modulo: {:?}. Our frame: {}",
                 pkt, self.frame
-            );
+            )));
         }
+        Ok(())
     }
 
-    fn on_received_commit_frame(&mut self, pkt: CommitFramePacket) -> bool {
-        if pkt.0 == SessionPacket::modulo(self.frame) {
+    fn on_received_commit_frame(&mut self, pkt: CommitFramePacket) -> NetResult<()> {
+        if pkt.0 == SessionPacket::modulo(self.frame) && !self.has_remote_committed_frame {
             self.has_remote_committed_frame = true;
             if self.has_committed_frame {
+                if let Some(since) = self.waiting_on_remote_since.take() {
+                    if self.stalled {
+                        self.stats.record_stall(since.elapsed());
+                    }
+                }
                 self.buffered_outcomes.push(Outcome::RunFrame);
-                true
-            } else {
-                false
             }
-        } else if pkt.0 == SessionPacket::modulo(self.frame + 1) {
+        } else if pkt.0 == SessionPacket::modulo(self.frame + 1)
+            && !self.has_remote_committed_next_frame
+        {
             self.has_remote_committed_next_frame = true;
-            false
         } else {
-            panic!(
+            return Err(out_of_sync(format!(
                 "Received commit with unexpected frame modulo: {:?}. Our frame: {}",
                 pkt, self.frame
-            );
+            )));
+        }
+        Ok(())
+    }
+
+    fn ping(&mut self) -> OutgoingPacket {
+        let sequence = self.next_ping;
+        self.next_ping = (self.next_ping + 1) % 32;
+        self.pending_pings[sequence as usize] = Some(Instant::now());
+        OutgoingPacket(SessionPacket::Ping(sequence))
+    }
+
+    fn on_received_pong(&mut self, sequence: u8) {
+        if let Some(sent_at) = self.pending_pings[sequence as usize].take() {
+            self.stats.record_rtt(sent_at.elapsed());
         }
     }
 
+    fn on_received_chat(&mut self, chat: ChatPacket) {
+        self.buffered_outcomes.push(Outcome::Chat {
+            player_i: self.remote_player,
+            text: chat.0,
+        });
+    }
+
     fn on_received_good_bye(&mut self) {
         self.buffered_outcomes
             .push(Outcome::RemoteLeft { politely: true });
@@ -307,73 +678,65 @@ struct OutgoingPacket(SessionPacket);
 
 pub type NetResult<T> = Result<T, std::io::Error>;
 
-fn run_socket_reader(
-    mut socket: TcpStream,
-    sender: Sender<ThreadMessage>,
-    session: Arc<Mutex<Session>>,
-) {
+async fn run_socket_reader(mut socket: TransportReader, events: UnboundedSender<NetworkEvent>) {
     let mut buf = Vec::new();
     let mut read_buf = [0; 1024];
     loop {
-        match socket.read(&mut read_buf) {
+        match socket.read(&mut read_buf).await {
+            Ok(0) => {
+                if events.send(NetworkEvent::Disconnected).is_err() {
+                    // no receiver (i.e. the game has ended)
+                }
+                return;
+            }
             Ok(n) => {
                 buf.extend_from_slice(&read_buf[..n]);
 
-                for byte in &buf {
-                    let packet = match SessionPacket::parse(*byte) {
-                        Some(msg) => msg,
-                        None => {
-                            let msg = ThreadMessage::Network(NetworkEvent::ReceiveError(format!(
-                                "Received bad byte: {:?}",
-                                byte
-                            )));
-                            if sender.send(msg).is_err() {
-                                // no receiver (i.e. main thread has exited)
-                            }
-                            return;
-                        }
+                let mut consumed = 0;
+                while consumed < buf.len() {
+                    let byte = buf[consumed];
+
+                    let packet = if byte == ChatPacket::MARKER {
+                        let (chat, len) = match ChatPacket::parse(&buf[consumed..]) {
+                            Some(parsed) => parsed,
+                            // The rest of the message hasn't arrived yet
+                            None => break,
+                        };
+                        consumed += len;
+                        chat.map(IncomingPacket::Chat)
+                            .ok_or_else(|| "Received chat message with invalid UTF-8".to_string())
+                    } else {
+                        consumed += 1;
+                        SessionPacket::parse(byte)
+                            .map(IncomingPacket::Session)
+                            .ok_or_else(|| format!("Received bad byte: {:?}", byte))
                     };
 
-                    let mut session = session.lock().unwrap();
-
-                    let mut remote_left = false;
-
-                    let new_outcomes = match packet {
-                        SessionPacket::SetDirection(pkt) => session.on_received_set_direction(pkt),
-                        SessionPacket::CommitFrame(pkt) => session.on_received_commit_frame(pkt),
-                        SessionPacket::GoodBye => {
-                            remote_left = true;
-                            session.on_received_good_bye();
-                            true
+                    let (event, done) = match packet {
+                        Ok(packet) => {
+                            let remote_left =
+                                matches!(packet, IncomingPacket::Session(SessionPacket::GoodBye));
+                            (NetworkEvent::Received(ReceivedPacket(packet)), remote_left)
                         }
+                        Err(error) => (NetworkEvent::Error(error), true),
                     };
-
-                    if new_outcomes {
-                        let event = NetworkEvent::BufferedOutcomes;
-                        if sender.send(ThreadMessage::Network(event)).is_err() {
-                            // no receiver (i.e. main thread has exited)
-                            return;
-                        }
+                    if events.send(event).is_err() {
+                        // no receiver (i.e. the game has ended)
+                        return;
                     }
-                    if remote_left {
+                    if done {
                         return;
                     }
                 }
-                buf.clear();
+                buf.drain(..consumed);
             }
             Err(error) => {
                 let event = match error.kind() {
-                    ErrorKind::ConnectionReset => {
-                        let mut session = session.lock().unwrap();
-                        session
-                            .buffered_outcomes
-                            .push(Outcome::RemoteLeft { politely: false });
-                        NetworkEvent::BufferedOutcomes
-                    }
-                    _ => NetworkEvent::ReceiveError(format!("Failed to read: {:?}", error)),
+                    ErrorKind::ConnectionReset => NetworkEvent::Disconnected,
+                    _ => NetworkEvent::Error(format!("Failed to read: {:?}", error)),
                 };
-                if sender.send(ThreadMessage::Network(event)).is_err() {
-                    // no receiver (i.e. main thread has exited)
+                if events.send(event).is_err() {
+                    // no receiver (i.e. the game has ended)
                 }
                 return;
             }
@@ -381,17 +744,65 @@ fn run_socket_reader(
     }
 }
 
+async fn run_socket_writer(
+    mut socket: TransportWriter,
+    mut outgoing: UnboundedReceiver<Vec<u8>>,
+    events: UnboundedSender<NetworkEvent>,
+) {
+    while let Some(bytes) = outgoing.recv().await {
+        if let Err(error) = socket.write_all(&bytes).await {
+            let event = match error.kind() {
+                ErrorKind::ConnectionReset | ErrorKind::BrokenPipe => NetworkEvent::Disconnected,
+                _ => NetworkEvent::Error(format!("Failed to write: {:?}", error)),
+            };
+            if events.send(event).is_err() {
+                // no receiver (i.e. the game has ended)
+            }
+            return;
+        }
+    }
+    // All senders are gone, i.e. we have said goodbye
+    if socket.shutdown().await.is_err() {
+        // the remote may already have hung up
+    }
+}
+
 #[derive(Debug)]
 pub enum NetworkEvent {
-    BufferedOutcomes,
-    ReceiveError(String),
+    Received(ReceivedPacket),
+    Disconnected,
+    Error(String),
+}
+
+#[derive(Debug)]
+pub struct ReceivedPacket(IncomingPacket);
+
+#[derive(Debug)]
+enum IncomingPacket {
+    Session(SessionPacket),
+    Chat(ChatPacket),
 }
 
 #[derive(Debug)]
 pub enum Outcome {
     PlayerControl(PlayerControlOutcome),
+    /// The player boosted in the current frame, see `Player::boost`
+    PlayerBoost(PlayerIndex),
     RunFrame,
-    RemoteLeft { politely: bool },
+    Chat {
+        player_i: PlayerIndex,
+        text: String,
+    },
+    RemoteWantsRematch {
+        swap_sides: bool,
+    },
+    Rematch {
+        swap_sides: bool,
+        frame: u32,
+    },
+    RemoteLeft {
+        politely: bool,
+    },
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -413,21 +824,51 @@ impl PlayerControlOutcome {
 struct ChooseNamePacket(String);
 
 impl ChooseNamePacket {
-    fn read(reader: &mut dyn Read) -> Self {
+    async fn read(reader: &mut Transport) -> NetResult<Self> {
         let mut len = [0];
-        reader.read_exact(&mut len).unwrap();
+        reader.read_exact(&mut len).await?;
         let len = u8::from_be_bytes(len);
         let mut name = vec![0; len as usize];
-        reader.read_exact(&mut name).unwrap();
-        let name = String::from_utf8(name).unwrap();
-        Self(name)
+        reader.read_exact(&mut name).await?;
+        let name = String::from_utf8(name).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        Ok(Self(name))
     }
 
-    fn write(&self, writer: &mut dyn Write) {
+    async fn write(&self, writer: &mut Transport) -> NetResult<()> {
         let name = self.0.as_bytes();
         let len = name.len() as u8;
-        writer.write_all(&[len]).unwrap();
-        writer.write_all(name).unwrap();
+        writer.write_all(&[len]).await?;
+        writer.write_all(name).await
+    }
+}
+
+pub const MAX_CHAT_MESSAGE_LEN: usize = u8::MAX as usize;
+
+#[derive(Debug, Clone)]
+struct ChatPacket(String);
+
+impl ChatPacket {
+    // Not a valid SessionPacket, so it can prefix a variable length message in the same stream:
+    // 10000100 llllllll <l bytes of UTF-8>
+    const MARKER: u8 = 0b_1000_0100;
+
+    /// Returns None if `buf` doesn't hold the whole message yet. The inner None means that
+    /// the message arrived in full but was not valid UTF-8.
+    fn parse(buf: &[u8]) -> Option<(Option<Self>, usize)> {
+        let len = *buf.get(1)? as usize;
+        let text = buf.get(2..2 + len)?;
+        let chat = String::from_utf8(text.to_vec()).ok().map(Self);
+        Some((chat, 2 + len))
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut len = self.0.len().min(MAX_CHAT_MESSAGE_LEN);
+        while !self.0.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut bytes = vec![Self::MARKER, len as u8];
+        bytes.extend_from_slice(&self.0.as_bytes()[..len]);
+        bytes
     }
 }
 
@@ -435,28 +876,81 @@ impl ChooseNamePacket {
 struct ChooseGameSizePacket((u16, u16));
 
 impl ChooseGameSizePacket {
-    fn read(reader: &mut dyn Read) -> Self {
+    async fn read(reader: &mut Transport) -> NetResult<Self> {
         let mut w_buf = [0; 2];
-        reader.read_exact(&mut w_buf).unwrap();
+        reader.read_exact(&mut w_buf).await?;
         let mut h_buf = [0; 2];
-        reader.read_exact(&mut h_buf).unwrap();
+        reader.read_exact(&mut h_buf).await?;
         let game_size = (u16::from_be_bytes(w_buf), u16::from_be_bytes(h_buf));
-        Self(game_size)
+        Ok(Self(game_size))
     }
 
-    fn write(&self, writer: &mut dyn Write) {
+    async fn write(&self, writer: &mut Transport) -> NetResult<()> {
         let game_size = self.0;
         let w = game_size.0.to_be_bytes();
         let h = game_size.1.to_be_bytes();
-        writer.write_all(&w).unwrap();
-        writer.write_all(&h).unwrap();
+        writer.write_all(&w).await?;
+        writer.write_all(&h).await
+    }
+}
+
+// The index of the rule in `TieRule::ALL`
+#[derive(Debug, Clone, Copy)]
+struct ChooseTieRulePacket(TieRule);
+
+impl ChooseTieRulePacket {
+    async fn read(reader: &mut Transport) -> NetResult<Self> {
+        let mut buf = [0];
+        reader.read_exact(&mut buf).await?;
+        match TieRule::ALL.get(buf[0] as usize) {
+            Some(tie_rule) => Ok(Self(*tie_rule)),
+            None => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown tie rule {}", buf[0]),
+            )),
+        }
+    }
+
+    async fn write(&self, writer: &mut Transport) -> NetResult<()> {
+        let i = TieRule::ALL
+            .iter()
+            .position(|rule| *rule == self.0)
+            .unwrap();
+        writer.write_all(&[i as u8]).await
+    }
+}
+
+// 1 if power-ups are on, else 0
+#[derive(Debug, Clone, Copy)]
+struct ChoosePowerUpsPacket(bool);
+
+impl ChoosePowerUpsPacket {
+    async fn read(reader: &mut Transport) -> NetResult<Self> {
+        let mut buf = [0];
+        reader.read_exact(&mut buf).await?;
+        match buf[0] {
+            0 => Ok(Self(false)),
+            1 => Ok(Self(true)),
+            byte => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Bad power-ups byte {}", byte),
+            )),
+        }
+    }
+
+    async fn write(&self, writer: &mut Transport) -> NetResult<()> {
+        writer.write_all(&[self.0 as u8]).await
     }
 }
 
 #[derive(Debug, Copy, Clone)]
 enum SessionPacket {
     SetDirection(SetDirectionPacket),
+    Boost(u8),
     CommitFrame(CommitFramePacket),
+    Ping(u8),
+    Pong(u8),
+    Rematch { swap_sides: bool },
     GoodBye,
 }
 
@@ -486,23 +980,43 @@ impl CommitFramePacket {
 
 impl SessionPacket {
     // 10000000 = GoodBye
+    // 10000100 = (reserved for ChatPacket)
+    // 10001000 = Rematch
+    // 10001100 = Rematch, swapping sides
+    // 11ffff00 = Boost(frame)
     // 1fffff11 = CommitFrame(frame)
+    // 1sssss01 = Ping(sequence)
+    // 1sssss10 = Pong(sequence)
     // 0fffffdd = SetDirection(frame, direction)
     // 0     00 = UP
     // 0     01 = LEFT
     // 0     10 = DOWN
     // 0     11 = RIGHT
     // _fffff__ = FRAME % 32
+    // _sssss__ = SEQUENCE % 32
+    // __ffff__ = FRAME % 16, for Boost
 
     fn parse(byte: u8) -> Option<Self> {
-        if byte == 0b_1000_0000 {
-            return Some(SessionPacket::GoodBye);
+        match byte {
+            0b_1000_0000 => return Some(SessionPacket::GoodBye),
+            0b_1000_1000 => return Some(SessionPacket::Rematch { swap_sides: false }),
+            0b_1000_1100 => return Some(SessionPacket::Rematch { swap_sides: true }),
+            _ => {}
         }
 
         let frame_modulo = (byte & 0b_0111_1100) >> 2;
 
+        if (byte & 0b_1100_0011) == 0b_1100_0000 {
+            return Some(SessionPacket::Boost((byte & 0b_0011_1100) >> 2));
+        }
+
         if (byte & 0b_1000_0000) != 0 {
-            return Some(SessionPacket::CommitFrame(CommitFramePacket(frame_modulo)));
+            return match byte & 0b_11 {
+                0b_11 => Some(SessionPacket::CommitFrame(CommitFramePacket(frame_modulo))),
+                0b_01 => Some(SessionPacket::Ping(frame_modulo)),
+                0b_10 => Some(SessionPacket::Pong(frame_modulo)),
+                _ => None,
+            };
         }
 
         let direction = match byte & 0b_11 {
@@ -521,9 +1035,14 @@ impl SessionPacket {
     fn serialize(&self) -> u8 {
         match self {
             SessionPacket::GoodBye => 0b_1000_0000,
+            SessionPacket::Rematch { swap_sides: false } => 0b_1000_1000,
+            SessionPacket::Rematch { swap_sides: true } => 0b_1000_1100,
             SessionPacket::CommitFrame(CommitFramePacket(frame_modulo)) => {
                 0b_1000_0011 | (frame_modulo << 2)
             }
+            SessionPacket::Boost(frame_modulo) => 0b_1100_0000 | (frame_modulo << 2),
+            SessionPacket::Ping(sequence) => 0b_1000_0001 | (sequence << 2),
+            SessionPacket::Pong(sequence) => 0b_1000_0010 | (sequence << 2),
             SessionPacket::SetDirection(SetDirectionPacket {
                 frame_modulo,
                 direction,
@@ -544,4 +1063,138 @@ impl SessionPacket {
     fn modulo(frame: u32) -> u8 {
         (frame % 32) as u8
     }
+
+    // Boosts have one bit less for the frame
+    fn boost_modulo(frame: u32) -> u8 {
+        (frame % 16) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> Session {
+        let mut session = Session::new(0, 1, RIGHT, 1);
+        session.round_over = true;
+        session
+    }
+
+    #[test]
+    fn asking_for_a_rematch_twice_sends_one_packet() {
+        let mut session = session();
+        let (packet, _) = session.request_rematch(false);
+        assert!(packet.is_some());
+        let (packet, outcomes) = session.request_rematch(true);
+        assert!(packet.is_none());
+        assert!(outcomes.is_empty());
+    }
+
+    #[test]
+    fn no_rematch_is_asked_for_during_a_round() {
+        let mut session = Session::new(0, 1, RIGHT, 1);
+        let (packet, _) = session.request_rematch(false);
+        assert!(packet.is_none());
+    }
+
+    #[test]
+    fn a_late_rematch_packet_does_not_carry_over_to_the_next_round() {
+        let mut session = session();
+        session.on_received_rematch(false);
+        let (_, outcomes) = session.request_rematch(false);
+        assert!(matches!(
+            outcomes[..],
+            [Outcome::RemoteWantsRematch { .. }, Outcome::Rematch { .. }]
+        ));
+
+        // The remote's repeated packet arrives once the new round has started
+        session.on_received_rematch(false);
+        assert!(session.buffered_outcomes.is_empty());
+
+        session.round_over = true;
+        let (_, outcomes) = session.request_rematch(false);
+        assert!(outcomes.is_empty(), "{:?}", outcomes);
+    }
+
+    #[test]
+    fn a_remote_commit_before_the_next_tick_is_not_a_stall() {
+        let mut session = Session::new(0, 1, RIGHT, 1);
+        session.commit_frame();
+        session
+            .on_received_commit_frame(CommitFramePacket::new(1))
+            .unwrap();
+        session.start_new_frame(2, RIGHT);
+        assert_eq!(session.stats.frames_waited_on_remote, 0);
+        assert_eq!(session.stats.longest_stall, Duration::ZERO);
+    }
+
+    #[test]
+    fn a_frame_that_waits_through_ticks_is_one_stall() {
+        let mut session = Session::new(0, 1, RIGHT, 1);
+        session.commit_frame();
+        session.commit_frame();
+        session.commit_frame();
+        session
+            .on_received_commit_frame(CommitFramePacket::new(1))
+            .unwrap();
+        assert_eq!(session.stats.frames_waited_on_remote, 1);
+        assert!(session.stats.longest_stall > Duration::ZERO);
+    }
+
+    #[test]
+    fn the_direction_that_the_game_has_is_sent_with_a_new_frame() {
+        let mut session = Session::new(0, 1, RIGHT, 1);
+        session.set_direction(UP);
+        session.commit_frame();
+        session
+            .on_received_commit_frame(CommitFramePacket::new(1))
+            .unwrap();
+
+        // The game turned the turn down, so the player is still heading right
+        let (packets, _) = session.start_new_frame(2, RIGHT);
+        assert!(matches!(
+            packets[..],
+            [OutgoingPacket(SessionPacket::SetDirection(
+                SetDirectionPacket {
+                    direction: RIGHT,
+                    ..
+                }
+            ))]
+        ));
+    }
+
+    #[test]
+    fn packets_for_a_frame_that_is_not_the_current_or_next_are_an_error() {
+        let mut session = Session::new(0, 1, RIGHT, 1);
+        let error = session
+            .on_received_commit_frame(CommitFramePacket::new(5))
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(session
+            .on_received_set_direction(SetDirectionPacket::new(3, UP))
+            .is_err());
+        assert!(session
+            .on_received_boost(SessionPacket::boost_modulo(0))
+            .is_err());
+        assert!(session.buffered_outcomes.is_empty());
+    }
+
+    #[test]
+    fn packets_for_a_frame_that_the_remote_has_committed_are_an_error() {
+        let mut session = Session::new(0, 1, RIGHT, 1);
+        for frame in [1, 2] {
+            session
+                .on_received_commit_frame(CommitFramePacket::new(frame))
+                .unwrap();
+            assert!(session
+                .on_received_commit_frame(CommitFramePacket::new(frame))
+                .is_err());
+            assert!(session
+                .on_received_set_direction(SetDirectionPacket::new(frame, UP))
+                .is_err());
+            assert!(session
+                .on_received_boost(SessionPacket::boost_modulo(frame))
+                .is_err());
+        }
+    }
 }
@@ -0,0 +1,409 @@
+use crate::config::{parse_color, parse_tie_rule};
+use crate::events;
+use crate::game::{parse_direction, Direction, FrameEvent, Game, Player, PlayerIndex};
+#[cfg(feature = "tui")]
+use crate::user_interface::TerminalUi;
+use crate::Point;
+use anyhow::{anyhow, bail, Context, Result};
+#[cfg(feature = "tui")]
+use crossterm::event::Event::Key;
+#[cfg(feature = "tui")]
+use crossterm::event::{EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+#[cfg(feature = "tui")]
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+#[cfg(feature = "tui")]
+use std::time::Duration;
+#[cfg(feature = "tui")]
+use tokio::time::{self, MissedTickBehavior};
+#[cfg(feature = "tui")]
+use tui::style::Color;
+
+/// A round read back from an event log (see `EventLog`), to be played again frame by frame.
+/// The game is run from the logged start with the logged turns and boosts, and every frame is
+/// checked against what the log says happened in it, so that a log the game can't reproduce
+/// (e.g. from another version of it) is caught rather than shown wrong.
+pub struct Replay {
+    pub game: Game,
+    pub round: u32,
+    frames: BTreeMap<u32, LoggedFrame>,
+    // The last frame that the log has anything about
+    last_frame: u32,
+}
+
+// What the log says about a frame
+#[derive(Default)]
+struct LoggedFrame {
+    directions: Vec<(PlayerIndex, Direction)>,
+    boosts: Vec<PlayerIndex>,
+    // The boosts, crashes, wins and power-ups, as logged, so that a boost the game refuses is
+    // caught too
+    events: Vec<Value>,
+}
+
+// The lines of the log that a replay is made from. The rest are only checked against.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Line {
+    RoundStart(RoundStart),
+    Direction {
+        player: PlayerIndex,
+        direction: String,
+    },
+    Boost {
+        player: PlayerIndex,
+    },
+    RemoteLeft {},
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct RoundStart {
+    frame: u32,
+    round: u32,
+    size: (u16, u16),
+    tie: String,
+    power_ups: bool,
+    players: Vec<LoggedPlayer>,
+}
+
+#[derive(Deserialize)]
+struct LoggedPlayer {
+    name: String,
+    color: String,
+    start: Point,
+    direction: String,
+    speed: u32,
+}
+
+impl Replay {
+    /// Reads the `round`th round in the log, counting from 1, or else the last one
+    pub fn load(path: &Path, round: Option<usize>) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read event log {:?}", path))?;
+        Self::parse(&text, round).with_context(|| format!("Invalid event log {:?}", path))
+    }
+
+    pub fn parse(text: &str, round: Option<usize>) -> Result<Self> {
+        // A log is appended to, so it can hold the rounds of many games
+        let mut rounds: Vec<Vec<(usize, Value)>> = vec![];
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let value: Value = serde_json::from_str(line)
+                .with_context(|| format!("Line {} is not valid JSON", i + 1))?;
+            if value["type"] == "round_start" {
+                rounds.push(vec![]);
+            }
+            if let Some(lines) = rounds.last_mut() {
+                lines.push((i + 1, value));
+            }
+        }
+
+        let count = rounds.len();
+        let lines = match round {
+            Some(round) => rounds.into_iter().nth(round.saturating_sub(1)),
+            None => rounds.pop(),
+        };
+        let lines = match (lines, round) {
+            (Some(lines), _) => lines,
+            (None, Some(round)) if count > 0 => {
+                bail!(
+                    "There is no round {} in the log, which has {}",
+                    round,
+                    count
+                )
+            }
+            (None, _) => bail!("There are no rounds in the log"),
+        };
+
+        let mut replay = None;
+        for (line_number, value) in lines {
+            Self::read_line(&mut replay, &value)
+                .with_context(|| format!("Line {} can't be replayed", line_number))?;
+        }
+        Ok(replay.expect("A round has at least its round_start line"))
+    }
+
+    fn read_line(replay: &mut Option<Self>, value: &Value) -> Result<()> {
+        let line = Line::deserialize(value)?;
+        match replay {
+            Some(replay) => replay.add(line, value),
+            None => match line {
+                Line::RoundStart(start) => {
+                    *replay = Some(Self::start(start)?);
+                    Ok(())
+                }
+                _ => unreachable!("A round starts with its round_start line"),
+            },
+        }
+    }
+
+    fn start(start: RoundStart) -> Result<Self> {
+        let players = start
+            .players
+            .into_iter()
+            .map(|p| {
+                let color = parse_color(&p.color).map_err(|e| anyhow!(e))?;
+                let direction = parse_logged_direction(&p.direction)?;
+                let mut player = Player::new(p.name, color, (p.start, direction));
+                player.base_speed = p.speed;
+                Ok(player)
+            })
+            .collect::<Result<_>>()?;
+        let mut game = Game::new(start.size, players, start.frame);
+        game.tie_rule = parse_tie_rule(&start.tie).map_err(|e| anyhow!(e))?;
+        game.spawn_power_ups = start.power_ups;
+        Ok(Self {
+            game,
+            round: start.round,
+            frames: BTreeMap::new(),
+            last_frame: start.frame,
+        })
+    }
+
+    fn add(&mut self, line: Line, value: &Value) -> Result<()> {
+        let frame = match value["frame"].as_u64() {
+            Some(frame) => frame as u32,
+            None => bail!("The line has no frame"),
+        };
+        let logged = self.frames.entry(frame).or_default();
+        let players = self.game.players.len();
+        match line {
+            Line::RoundStart(_) => unreachable!("A round has a single round_start line"),
+            Line::Direction { player, .. } | Line::Boost { player } if player >= players => {
+                bail!("There is no player {} in a round of {}", player, players)
+            }
+            Line::Direction { player, direction } => {
+                let direction = parse_logged_direction(&direction)?;
+                logged.directions.push((player, direction));
+            }
+            Line::Boost { player } => {
+                logged.boosts.push(player);
+                logged.events.push(value.clone());
+            }
+            // It's logged as soon as it's noticed, before the frame is run
+            Line::RemoteLeft {} => {
+                self.last_frame = self.last_frame.max(frame.saturating_sub(1));
+                return Ok(());
+            }
+            Line::Other => {
+                if let Some("crashed" | "won" | "everyone_crashed" | "power_up") =
+                    value["type"].as_str()
+                {
+                    logged.events.push(value.clone());
+                }
+            }
+        }
+        self.last_frame = self.last_frame.max(frame);
+        Ok(())
+    }
+
+    /// Runs the next frame with the logged turns and boosts. Fails if what happens in it isn't
+    /// what the log says happened.
+    pub fn run_frame(&mut self) -> Result<Vec<FrameEvent>> {
+        let frame = self.game.frame;
+        let logged = self.frames.remove(&frame).unwrap_or_default();
+        for (i, direction) in logged.directions {
+            self.game.players[i].direction = direction;
+        }
+        for i in logged.boosts {
+            self.game.players[i].boost();
+        }
+
+        let events = self.game.run_frame();
+        let replayed: Vec<Value> = events
+            .iter()
+            .map(|event| events::event_line(frame, event))
+            .collect();
+        if replayed != logged.events {
+            bail!(
+                "Frame {} doesn't go as logged: {} instead of {}",
+                frame,
+                Value::from(replayed),
+                Value::from(logged.events)
+            );
+        }
+        Ok(events)
+    }
+
+    /// Whether the round is over, or the log has nothing more about it
+    pub fn is_over(&self) -> bool {
+        self.game.game_over || self.game.frame > self.last_frame
+    }
+}
+
+fn parse_logged_direction(name: &str) -> Result<Direction> {
+    parse_direction(name).ok_or_else(|| anyhow!("Unknown direction '{}'", name))
+}
+
+#[cfg(feature = "tui")]
+impl Replay {
+    /// Plays the round in the terminal, a frame per tick. Space pauses, and q or ctrl+c quits.
+    pub async fn show(mut self, tick: Duration) -> Result<()> {
+        let mut ui = TerminalUi::new(self.game.size(), self.game.players.clone());
+        ui.start_round(self.round, self.game.players.clone());
+        ui.set_banner(Color::Yellow, "Replay");
+        let mut clock = time::interval(tick);
+        clock.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut user_input = EventStream::new();
+        let mut paused = false;
+        let mut mismatch = None;
+
+        loop {
+            ui.draw()?;
+            tokio::select! {
+                _ = clock.tick() => {
+                    if !paused && mismatch.is_none() && !self.is_over() {
+                        match self.run_frame() {
+                            Ok(events) => ui.show_frame(&self.game, &events),
+                            Err(error) => {
+                                ui.set_banner(Color::Red, &format!("{:#}", error));
+                                mismatch = Some(error);
+                            }
+                        }
+                        if self.is_over() && !self.game.game_over {
+                            ui.set_banner(Color::Yellow, "The log of the round ends here");
+                        }
+                    }
+                }
+                Some(event) = user_input.next() => match event? {
+                    Key(KeyEvent {
+                        code: KeyCode::Char('c'),
+                        modifiers: KeyModifiers::CONTROL,
+                        kind: KeyEventKind::Press,
+                        state: _,
+                    }) => break,
+                    Key(KeyEvent {
+                        code: KeyCode::Char('q'),
+                        ..
+                    }) => break,
+                    Key(KeyEvent {
+                        code: KeyCode::Char(' '),
+                        kind: KeyEventKind::Press,
+                        ..
+                    }) => paused = !paused,
+                    _ => {}
+                },
+            }
+        }
+
+        // The terminal is given back before the error is printed
+        drop(ui);
+        match mismatch {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{EventLog, EventTarget};
+    use crate::game::{PlayerColor, StartPosition, RIGHT, UP};
+
+    // Plays a round with a turn, a handicap, a boost and power-ups, and logs it
+    fn record(name: &str) -> (Game, String) {
+        let path =
+            std::env::temp_dir().join(format!("achtung-{}-{}.jsonl", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        let size = (40, 20);
+        let starts = StartPosition::spread(2);
+        let players = (0..2)
+            .map(|i| {
+                let name = format!("Player {}", i + 1);
+                Player::new(name, PlayerColor::Blue, starts[i].resolve(size))
+            })
+            .collect();
+        let mut game = Game::new(size, players, 1);
+        game.spawn_power_ups = true;
+        game.players[1].base_speed = 80;
+
+        let mut log = EventLog::open(&EventTarget::File(path.clone())).unwrap();
+        log.round_start(&game, 1, "offline").unwrap();
+        while !game.game_over {
+            match game.frame {
+                3 => game.players[0].steer(UP),
+                5 => game.players[1].boost(),
+                8 => game.players[0].steer(RIGHT),
+                _ => false,
+            };
+            let events = game.run_frame();
+            log.frame(&game, &events).unwrap();
+        }
+        drop(log);
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        (game, text)
+    }
+
+    #[test]
+    fn a_logged_round_is_replayed_as_it_was_played() {
+        let (game, text) = record("replay");
+        let mut replay = Replay::parse(&text, None).unwrap();
+        while !replay.is_over() {
+            replay.run_frame().unwrap();
+        }
+        assert!(replay.game.game_over);
+        assert_eq!(replay.game.frame, game.frame);
+        for (replayed, played) in replay.game.players.iter().zip(&game.players) {
+            assert_eq!(replayed.line, played.line);
+            assert_eq!(replayed.crashed, played.crashed);
+        }
+    }
+
+    #[test]
+    fn a_log_that_the_game_does_not_reproduce_is_caught() {
+        let (_, text) = record("replay-mismatch");
+        let text: String = text
+            .lines()
+            .filter(|line| !line.contains("\"type\":\"direction\""))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        let mut replay = Replay::parse(&text, Some(1)).unwrap();
+        let mut result = Ok(vec![]);
+        while result.is_ok() && !replay.is_over() {
+            result = replay.run_frame();
+        }
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn a_logged_boost_that_the_game_turns_down_is_caught() {
+        let (_, text) = record("replay-boost");
+        // Another boost a frame later, while the first one is still cooling down
+        let text: String = text
+            .lines()
+            .flat_map(|line| {
+                if line.contains("\"type\":\"boost\"") {
+                    vec![line.to_string(), line.replace("\"frame\":5", "\"frame\":6")]
+                } else {
+                    vec![line.to_string()]
+                }
+            })
+            .map(|line| format!("{}\n", line))
+            .collect();
+        assert_eq!(text.matches("\"type\":\"boost\"").count(), 2);
+        let mut replay = Replay::parse(&text, None).unwrap();
+        let mut result = Ok(vec![]);
+        while result.is_ok() && !replay.is_over() {
+            result = replay.run_frame();
+        }
+        assert!(result.is_err());
+        assert_eq!(replay.game.frame, 7);
+    }
+
+    #[test]
+    fn a_round_that_is_not_in_the_log_is_an_error() {
+        let (_, text) = record("replay-rounds");
+        assert!(Replay::parse(&text, Some(2)).is_err());
+        assert!(Replay::parse("", None).is_err());
+    }
+}
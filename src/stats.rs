@@ -0,0 +1,488 @@
+use crate::game::{FrameEvent, Game};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A round that was played to the end
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GameRecord {
+    /// Seconds since the Unix epoch
+    pub finished_at: u64,
+    /// `offline`, `host` or `client`
+    pub mode: String,
+    pub size: (u16, u16),
+    pub frames: u32,
+    pub winner: Option<String>,
+    pub participants: Vec<Participant>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Participant {
+    pub name: String,
+    /// Frames until the player crashed, or until the end of the round
+    pub survived: u32,
+    /// Players that crashed into this player's line
+    pub kills: u32,
+}
+
+/// Where finished rounds are recorded, when no other file is given
+pub fn default_path() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("achtung").join("games.jsonl"))
+}
+
+/// The finished rounds, as a file with one `GameRecord` per line that is only ever appended to
+pub struct StatsStore {
+    path: PathBuf,
+}
+
+impl StatsStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Every round recorded so far, oldest first. A file that doesn't exist yet has none.
+    pub fn load(&self) -> Result<Vec<GameRecord>> {
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(error) => {
+                return Err(error)
+                    .with_context(|| format!("Failed to read stats file {:?}", self.path))
+            }
+        };
+        let mut records = vec![];
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line =
+                line.with_context(|| format!("Failed to read stats file {:?}", self.path))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record = serde_json::from_str(&line)
+                .with_context(|| format!("Invalid stats file {:?}, line {}", self.path, i + 1))?;
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    pub fn append(&self, record: &GameRecord) -> Result<()> {
+        let write = || -> std::io::Result<()> {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            // One write per line, so that two games finishing at once don't interleave
+            let line = serde_json::to_string(record)? + "\n";
+            file.write_all(line.as_bytes())
+        };
+        write().with_context(|| format!("Failed to write stats file {:?}", self.path))
+    }
+}
+
+/// Follows a round as it's played, and records it in the store once it's over. Rounds that are
+/// abandoned halfway aren't recorded.
+pub struct StatsRecorder {
+    store: StatsStore,
+    records: Vec<GameRecord>,
+    mode: String,
+    start_frame: u32,
+    // Frames survived by each player, for the ones that have crashed
+    crashed_after: Vec<Option<u32>>,
+}
+
+impl StatsRecorder {
+    pub fn open(store: StatsStore) -> Result<Self> {
+        Ok(Self {
+            records: store.load()?,
+            store,
+            mode: String::new(),
+            start_frame: 1,
+            crashed_after: vec![],
+        })
+    }
+
+    /// `mode` is `offline`, `host` or `client`
+    pub fn round_start(&mut self, game: &Game, mode: &str) {
+        self.mode = mode.to_string();
+        self.start_frame = game.frame;
+        self.crashed_after = vec![None; game.players.len()];
+    }
+
+    /// Follows a frame that has just been run, and records the round if it ended with it
+    pub fn frame(&mut self, game: &Game, events: &[FrameEvent]) -> Result<()> {
+        let frames = game.frame - self.start_frame;
+        let mut winner = None;
+        for event in events {
+            match event {
+                FrameEvent::PlayerCrashed(i, _) => self.crashed_after[*i] = Some(frames),
+                FrameEvent::PlayerWon(i) => winner = Some(game.players[*i].name.clone()),
+                FrameEvent::Boosted(_)
+                | FrameEvent::EveryoneCrashed
+                | FrameEvent::PowerUpTaken(..) => {}
+            }
+        }
+        if !game.game_over {
+            return Ok(());
+        }
+
+        let finished_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0);
+        let record = GameRecord {
+            finished_at,
+            mode: self.mode.clone(),
+            size: game.size(),
+            frames,
+            winner,
+            participants: game
+                .players
+                .iter()
+                .zip(&self.crashed_after)
+                .map(|(player, crashed_after)| Participant {
+                    name: player.name.clone(),
+                    survived: crashed_after.unwrap_or(frames),
+                    kills: player.kills,
+                })
+                .collect(),
+        };
+        self.store.append(&record)?;
+        self.records.push(record);
+        Ok(())
+    }
+
+    pub fn leaderboard(&self, names: &[String]) -> Leaderboard {
+        Leaderboard::new(&self.records, names)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Leaderboard {
+    pub games: u32,
+    /// Best win rate first
+    pub players: Vec<PlayerStats>,
+    pub head_to_head: Vec<HeadToHead>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlayerStats {
+    pub name: String,
+    pub games: u32,
+    pub wins: u32,
+    pub win_rate: f64,
+    pub average_survival: f64,
+    pub kills: u32,
+    /// Rounds won in a row, up to the latest one
+    pub streak: u32,
+    pub best_streak: u32,
+}
+
+/// How two players did in the rounds they played together, counted by who outlasted whom
+#[derive(Debug, Clone, Serialize)]
+pub struct HeadToHead {
+    pub player: String,
+    pub opponent: String,
+    pub wins: u32,
+    pub losses: u32,
+    pub draws: u32,
+}
+
+impl Leaderboard {
+    /// With `names`, only those players and the records between them are included. Everyone's
+    /// numbers are still counted over all of their rounds.
+    ///
+    /// A record only has the names of the players, so players are told apart by name alone: the
+    /// rounds of everyone with the same name are counted together, and there is no head-to-head
+    /// record between players that have the same name.
+    pub fn new(records: &[GameRecord], names: &[String]) -> Self {
+        let included = |name: &str| names.is_empty() || names.iter().any(|n| n == name);
+        let mut players: Vec<PlayerStats> = vec![];
+        let mut total_survival: Vec<u64> = vec![];
+        let mut head_to_head: Vec<HeadToHead> = vec![];
+
+        for record in records {
+            for participant in &record.participants {
+                if !included(&participant.name) {
+                    continue;
+                }
+                let i = match players.iter().position(|p| p.name == participant.name) {
+                    Some(i) => i,
+                    None => {
+                        players.push(PlayerStats {
+                            name: participant.name.clone(),
+                            games: 0,
+                            wins: 0,
+                            win_rate: 0.0,
+                            average_survival: 0.0,
+                            kills: 0,
+                            streak: 0,
+                            best_streak: 0,
+                        });
+                        total_survival.push(0);
+                        players.len() - 1
+                    }
+                };
+                let stats = &mut players[i];
+                stats.games += 1;
+                stats.kills += participant.kills;
+                total_survival[i] += participant.survived as u64;
+                if record.winner.as_ref() == Some(&participant.name) {
+                    stats.wins += 1;
+                    stats.streak += 1;
+                    stats.best_streak = stats.best_streak.max(stats.streak);
+                } else {
+                    stats.streak = 0;
+                }
+            }
+
+            for (a, first) in record.participants.iter().enumerate() {
+                for second in &record.participants[a + 1..] {
+                    if !included(&first.name)
+                        || !included(&second.name)
+                        || first.name == second.name
+                    {
+                        continue;
+                    }
+                    let (player, opponent) = if first.name <= second.name {
+                        (first, second)
+                    } else {
+                        (second, first)
+                    };
+                    let pair = match head_to_head
+                        .iter()
+                        .position(|h| h.player == player.name && h.opponent == opponent.name)
+                    {
+                        Some(i) => &mut head_to_head[i],
+                        None => {
+                            head_to_head.push(HeadToHead {
+                                player: player.name.clone(),
+                                opponent: opponent.name.clone(),
+                                wins: 0,
+                                losses: 0,
+                                draws: 0,
+                            });
+                            head_to_head.last_mut().unwrap()
+                        }
+                    };
+                    // Whoever crashed in the last frame lasted as long as the winner did
+                    let won = |p: &Participant| record.winner.as_ref() == Some(&p.name);
+                    let outlasted = player
+                        .survived
+                        .cmp(&opponent.survived)
+                        .then(won(player).cmp(&won(opponent)));
+                    match outlasted {
+                        Ordering::Greater => pair.wins += 1,
+                        Ordering::Equal => pair.draws += 1,
+                        Ordering::Less => pair.losses += 1,
+                    }
+                }
+            }
+        }
+
+        for (stats, total) in players.iter_mut().zip(total_survival) {
+            stats.win_rate = stats.wins as f64 / stats.games as f64;
+            stats.average_survival = total as f64 / stats.games as f64;
+        }
+        players.sort_by(|a, b| {
+            b.win_rate
+                .total_cmp(&a.win_rate)
+                .then(b.games.cmp(&a.games))
+        });
+        head_to_head.sort_by(|a, b| (&a.player, &a.opponent).cmp(&(&b.player, &b.opponent)));
+
+        Self {
+            games: records.len() as u32,
+            players,
+            head_to_head,
+        }
+    }
+
+    /// The leaderboard as rows of text, each with the player it's about, if any
+    pub fn lines(&self) -> Vec<(Option<&str>, String)> {
+        if self.players.is_empty() {
+            return vec![(None, "No rounds recorded yet".to_string())];
+        }
+        let width = self
+            .players
+            .iter()
+            .map(|p| p.name.len())
+            .max()
+            .unwrap_or(0)
+            .max("Player".len());
+        let mut lines = vec![(
+            None,
+            format!(
+                "{:<width$}  {:>5}  {:>5}  {:>6}  {:>5}  {:>6}  {:>4}",
+                "Player", "Games", "Wins", "Win %", "Kills", "Streak", "Best"
+            ),
+        )];
+        for p in &self.players {
+            lines.push((
+                Some(p.name.as_str()),
+                format!(
+                    "{:<width$}  {:>5}  {:>5}  {:>5.1}%  {:>5}  {:>6}  {:>4}",
+                    p.name,
+                    p.games,
+                    p.wins,
+                    p.win_rate * 100.0,
+                    p.kills,
+                    p.streak,
+                    p.best_streak
+                ),
+            ));
+        }
+        if !self.head_to_head.is_empty() {
+            lines.push((None, String::new()));
+            lines.push((None, "Head to head (who outlasted whom)".to_string()));
+            for h in &self.head_to_head {
+                let draws = match h.draws {
+                    0 => String::new(),
+                    1 => ", 1 draw".to_string(),
+                    n => format!(", {} draws", n),
+                };
+                lines.push((
+                    None,
+                    format!(
+                        "{} {} - {} {}{}",
+                        h.player, h.wins, h.losses, h.opponent, draws
+                    ),
+                ));
+            }
+        }
+        lines
+    }
+}
+
+impl fmt::Display for Leaderboard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} rounds recorded", self.games)?;
+        writeln!(f)?;
+        for (_, line) in self.lines() {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A round of the players in `survived`, by name and frames survived
+    fn record(winner: Option<&str>, survived: &[(&str, u32)]) -> GameRecord {
+        GameRecord {
+            finished_at: 0,
+            mode: "offline".to_string(),
+            size: (35, 16),
+            frames: survived
+                .iter()
+                .map(|(_, frames)| *frames)
+                .max()
+                .unwrap_or(0),
+            winner: winner.map(str::to_string),
+            participants: survived
+                .iter()
+                .map(|(name, survived)| Participant {
+                    name: name.to_string(),
+                    survived: *survived,
+                    kills: 0,
+                })
+                .collect(),
+        }
+    }
+
+    fn stats<'a>(leaderboard: &'a Leaderboard, name: &str) -> &'a PlayerStats {
+        leaderboard.players.iter().find(|p| p.name == name).unwrap()
+    }
+
+    fn counts(leaderboard: &Leaderboard) -> Vec<(&str, &str, u32, u32, u32)> {
+        leaderboard
+            .head_to_head
+            .iter()
+            .map(|h| {
+                (
+                    h.player.as_str(),
+                    h.opponent.as_str(),
+                    h.wins,
+                    h.losses,
+                    h.draws,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_streak_is_broken_by_a_loss_or_a_draw() {
+        let records = [
+            record(Some("Alice"), &[("Alice", 10), ("Bob", 5)]),
+            record(Some("Alice"), &[("Alice", 10), ("Bob", 5)]),
+            record(Some("Alice"), &[("Alice", 10), ("Bob", 5)]),
+            record(None, &[("Alice", 7), ("Bob", 7)]),
+            record(Some("Alice"), &[("Alice", 10), ("Bob", 5)]),
+            record(Some("Bob"), &[("Alice", 5), ("Bob", 10)]),
+            record(Some("Alice"), &[("Alice", 10), ("Bob", 5)]),
+            record(Some("Alice"), &[("Alice", 10), ("Bob", 5)]),
+        ];
+        let leaderboard = Leaderboard::new(&records, &[]);
+        let alice = stats(&leaderboard, "Alice");
+        assert_eq!((alice.wins, alice.streak, alice.best_streak), (6, 2, 3));
+        let bob = stats(&leaderboard, "Bob");
+        assert_eq!((bob.wins, bob.streak, bob.best_streak), (1, 0, 1));
+
+        // A round without the player doesn't break their streak
+        let records = [
+            record(Some("Alice"), &[("Alice", 10), ("Bob", 5)]),
+            record(Some("Carol"), &[("Carol", 10), ("Bob", 5)]),
+            record(Some("Alice"), &[("Alice", 10), ("Carol", 5)]),
+        ];
+        let leaderboard = Leaderboard::new(&records, &[]);
+        assert_eq!(stats(&leaderboard, "Alice").streak, 2);
+    }
+
+    #[test]
+    fn head_to_head_counts_who_outlasted_whom() {
+        let records = [
+            // Carol outlasts Bob, but neither of them wins
+            record(Some("Alice"), &[("Alice", 20), ("Bob", 5), ("Carol", 12)]),
+            // Bob crashes in the last frame, as Alice wins it
+            record(Some("Alice"), &[("Bob", 9), ("Alice", 9)]),
+            record(None, &[("Alice", 7), ("Bob", 7), ("Carol", 3)]),
+            record(Some("Bob"), &[("Alice", 4), ("Bob", 8)]),
+        ];
+        let leaderboard = Leaderboard::new(&records, &[]);
+        assert_eq!(
+            counts(&leaderboard),
+            [
+                ("Alice", "Bob", 2, 1, 1),
+                ("Alice", "Carol", 2, 0, 0),
+                ("Bob", "Carol", 1, 1, 0),
+            ]
+        );
+
+        let leaderboard = Leaderboard::new(&records, &["Carol".to_string(), "Bob".to_string()]);
+        assert_eq!(counts(&leaderboard), [("Bob", "Carol", 1, 1, 0)]);
+        assert_eq!(leaderboard.players.len(), 2);
+    }
+
+    #[test]
+    fn players_with_the_same_name_have_no_record_against_each_other() {
+        let records = [record(
+            Some("Bot"),
+            &[("Bot", 10), ("Bot", 5), ("Alice", 3)],
+        )];
+        let leaderboard = Leaderboard::new(&records, &[]);
+        assert_eq!(counts(&leaderboard), [("Alice", "Bot", 0, 2, 0)]);
+        assert_eq!(stats(&leaderboard, "Bot").games, 2);
+    }
+}
@@ -0,0 +1,326 @@
+use crate::ai::AiKind;
+use crate::config::MAX_SEATS;
+use crate::game::{FrameEvent, Game, Player, PlayerColor, StartPosition};
+use serde::Serialize;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread;
+use std::time::Duration;
+
+const INITIAL_ELO: f64 = 1500.0;
+// How much a rating can move in one game, split over the opponents
+const ELO_K: f64 = 32.0;
+const DEFAULT_THINK_TIME: Duration = Duration::from_millis(5);
+
+/// Plays the strategies against each other, all of them in every game, without any UI or tick.
+/// The seats are rotated from game to game, so that every strategy gets every start position
+/// equally often (give a multiple of the number of strategies as `games` to make it exact).
+pub struct Tournament {
+    strategies: Vec<AiKind>,
+    pub games: u32,
+    pub size: (u16, u16),
+    // How long the search based strategies may think per frame
+    pub think_time: Duration,
+    pub threads: usize,
+    // Seeds the random choices of the strategies in every game, which are else left to chance
+    pub seed: Option<u64>,
+}
+
+struct GameResult {
+    game: u32,
+    // Frames that each strategy survived, in the order of `Tournament::strategies`
+    survival: Vec<u32>,
+    winner: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct Standings {
+    games: u32,
+    size: (u16, u16),
+    // Games that ended with the last players crashing at the same time
+    draws: u32,
+    strategies: Vec<Standing>,
+}
+
+#[derive(Serialize)]
+struct Standing {
+    name: String,
+    wins: u32,
+    win_rate: f64,
+    average_survival: f64,
+    elo: f64,
+}
+
+impl Tournament {
+    /// Fails unless there are 2 to `MAX_SEATS` strategies, which all have to fit in one arena.
+    /// The strategies think for 5ms per frame, on a single thread, unless that's changed.
+    pub fn new(strategies: Vec<AiKind>, games: u32, size: (u16, u16)) -> Result<Self, String> {
+        if !(2..=MAX_SEATS).contains(&strategies.len()) {
+            return Err(format!(
+                "A tournament takes 2 to {} strategies (got {})",
+                MAX_SEATS,
+                strategies.len()
+            ));
+        }
+        Ok(Self {
+            strategies,
+            games,
+            size,
+            think_time: DEFAULT_THINK_TIME,
+            threads: 1,
+            seed: None,
+        })
+    }
+
+    pub fn strategies(&self) -> &[AiKind] {
+        &self.strategies
+    }
+
+    pub fn run(&self) -> Standings {
+        let next_game = AtomicU32::new(0);
+        let mut results: Vec<GameResult> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..self.threads.max(1))
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut results = vec![];
+                        loop {
+                            let game = next_game.fetch_add(1, Ordering::Relaxed);
+                            if game >= self.games {
+                                return results;
+                            }
+                            results.push(self.play(game));
+                        }
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().expect("Tournament worker panicked"))
+                .collect()
+        });
+        // Elo depends on the order the games are counted in, which shouldn't depend on which
+        // thread happened to finish first
+        results.sort_by_key(|result| result.game);
+        self.standings(&results)
+    }
+
+    fn play(&self, game_i: u32) -> GameResult {
+        let count = self.strategies.len();
+        let start_positions = StartPosition::spread(count);
+        let seating = self.seating(game_i);
+        let mut players = vec![];
+        let mut strategies = vec![];
+        for (seat, &strategy_i) in seating.iter().enumerate() {
+            let kind = self.strategies[strategy_i];
+            players.push(Player::new(
+                kind.name().to_string(),
+                PlayerColor::Gray,
+                start_positions[seat].resolve(self.size),
+            ));
+            strategies.push(match self.seed {
+                Some(seed) => {
+                    // Every seat of every game gets its own seed
+                    let seat_seed = seed.wrapping_add(game_i as u64 * count as u64 + seat as u64);
+                    kind.create_seeded(self.think_time, seat_seed)
+                }
+                None => kind.create(self.think_time),
+            });
+        }
+
+        let mut game = Game::new(self.size, players, 1);
+        let mut survival = vec![0; count];
+        let mut frames = 0;
+        let mut winner = None;
+        while !game.game_over {
+            for (seat, strategy) in strategies.iter_mut().enumerate() {
+                if !game.players[seat].crashed {
+                    let direction = strategy.choose_direction(&game, seat);
+                    game.players[seat].steer(direction);
+                }
+            }
+            let events = game.run_frame();
+            frames += 1;
+            for event in events {
+                match event {
+                    FrameEvent::PlayerCrashed(seat, _) => survival[seating[seat]] = frames,
+                    FrameEvent::PlayerWon(seat) => winner = Some(seating[seat]),
+                    FrameEvent::Boosted(_)
+                    | FrameEvent::EveryoneCrashed
+                    | FrameEvent::PowerUpTaken(..) => {}
+                }
+            }
+        }
+        if let Some(winner) = winner {
+            survival[winner] = frames;
+        }
+
+        GameResult {
+            game: game_i,
+            survival,
+            winner,
+        }
+    }
+
+    // The strategy in each seat: seat `seat` is taken by strategy `(seat + game_i) % count`
+    fn seating(&self, game_i: u32) -> Vec<usize> {
+        let count = self.strategies.len();
+        (0..count)
+            .map(|seat| (seat + game_i as usize) % count)
+            .collect()
+    }
+
+    fn standings(&self, results: &[GameResult]) -> Standings {
+        let count = self.strategies.len();
+        let mut wins = vec![0; count];
+        let mut total_survival = vec![0u64; count];
+        let mut elo = vec![INITIAL_ELO; count];
+        for result in results {
+            if let Some(winner) = result.winner {
+                wins[winner] += 1;
+            }
+            for (total, survival) in total_survival.iter_mut().zip(&result.survival) {
+                *total += *survival as u64;
+            }
+            update_elo(&mut elo, &result.survival);
+        }
+
+        let games = results.len() as u32;
+        let mut strategies: Vec<Standing> = (0..count)
+            .map(|i| Standing {
+                name: self.entrant_name(i),
+                wins: wins[i],
+                win_rate: wins[i] as f64 / games.max(1) as f64,
+                average_survival: total_survival[i] as f64 / games.max(1) as f64,
+                elo: elo[i].round(),
+            })
+            .collect();
+        strategies.sort_by(|a, b| b.elo.total_cmp(&a.elo));
+
+        Standings {
+            games,
+            size: self.size,
+            draws: results.iter().filter(|r| r.winner.is_none()).count() as u32,
+            strategies,
+        }
+    }
+
+    // A strategy that is in the tournament more than once is numbered from the second time on
+    fn entrant_name(&self, i: usize) -> String {
+        let kind = self.strategies[i];
+        let earlier = self.strategies[..i].iter().filter(|k| **k == kind).count();
+        if earlier == 0 {
+            kind.name().to_string()
+        } else {
+            format!("{} #{}", kind.name(), earlier + 1)
+        }
+    }
+}
+
+// Every game counts as a match between each pair of strategies in it, won by whoever survived
+// longer. All pairs are rated from the ratings before the game.
+fn update_elo(elo: &mut [f64], survival: &[u32]) {
+    let k = ELO_K / (elo.len() - 1) as f64;
+    let before = elo.to_vec();
+    for a in 0..elo.len() {
+        for b in 0..elo.len() {
+            if a == b {
+                continue;
+            }
+            let expected = 1.0 / (1.0 + 10f64.powf((before[b] - before[a]) / 400.0));
+            let actual = match survival[a].cmp(&survival[b]) {
+                std::cmp::Ordering::Greater => 1.0,
+                std::cmp::Ordering::Equal => 0.5,
+                std::cmp::Ordering::Less => 0.0,
+            };
+            elo[a] += k * (actual - expected);
+        }
+    }
+}
+
+impl fmt::Display for Standings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} games on a {}x{} arena, {} of which everyone crashed",
+            self.games, self.size.0, self.size.1, self.draws
+        )?;
+        writeln!(f)?;
+        let width = self
+            .strategies
+            .iter()
+            .map(|s| s.name.len())
+            .max()
+            .unwrap_or(0)
+            .max("Strategy".len());
+        writeln!(
+            f,
+            "{:<width$}  {:>6}  {:>8}  {:>12}  {:>6}",
+            "Strategy", "Wins", "Win rate", "Avg survival", "Elo"
+        )?;
+        for s in &self.strategies {
+            writeln!(
+                f,
+                "{:<width$}  {:>6}  {:>7.1}%  {:>12.1}  {:>6}",
+                s.name,
+                s.wins,
+                s.win_rate * 100.0,
+                s.average_survival,
+                s.elo
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tournament(count: usize, games: u32) -> Tournament {
+        Tournament::new(vec![AiKind::default(); count], games, (20, 10)).unwrap()
+    }
+
+    #[test]
+    fn every_strategy_gets_every_seat_equally_often() {
+        for count in 2..=MAX_SEATS {
+            let games = count as u32 * 3;
+            let tournament = tournament(count, games);
+            // How often each strategy sat in each seat
+            let mut seats = vec![vec![0; count]; count];
+            for game_i in 0..games {
+                for (seat, strategy_i) in tournament.seating(game_i).into_iter().enumerate() {
+                    seats[strategy_i][seat] += 1;
+                }
+            }
+            assert!(seats.iter().flatten().all(|n| *n == 3), "{:?}", seats);
+        }
+    }
+
+    #[test]
+    fn a_tournament_takes_2_to_max_seats_strategies() {
+        assert!(Tournament::new(vec![AiKind::default()], 1, (20, 10)).is_err());
+        assert!(Tournament::new(vec![AiKind::default(); MAX_SEATS + 1], 1, (20, 10)).is_err());
+        assert!(Tournament::new(vec![AiKind::default(); MAX_SEATS], 1, (20, 10)).is_ok());
+    }
+
+    #[test]
+    fn elo_moves_from_whoever_survived_shorter_to_whoever_survived_longer() {
+        let mut elo = vec![INITIAL_ELO; 3];
+        update_elo(&mut elo, &[30, 10, 10]);
+        assert_eq!(elo[0], INITIAL_ELO + ELO_K / 2.0);
+        assert_eq!(elo[1], INITIAL_ELO - ELO_K / 4.0);
+        assert_eq!(elo[1], elo[2]);
+        assert_eq!(elo.iter().sum::<f64>(), INITIAL_ELO * 3.0);
+    }
+
+    #[test]
+    fn every_game_is_counted() {
+        let mut tournament = tournament(3, 6);
+        tournament.seed = Some(1);
+        tournament.threads = 2;
+        let standings = tournament.run();
+        assert_eq!(standings.games, 6);
+        let wins: u32 = standings.strategies.iter().map(|s| s.wins).sum();
+        assert_eq!(wins + standings.draws, 6);
+    }
+}
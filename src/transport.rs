@@ -0,0 +1,402 @@
+use crate::net::NetResult;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+const SALT_LEN: usize = 32;
+const MAX_RECORD_PLAINTEXT: usize = 4096;
+const KEY_CONFIRMATION: &[u8] = b"achtung-key-ok";
+
+/// The byte stream that `Networking` talks over: either the plain TCP socket, or the socket
+/// wrapped in ChaCha20-Poly1305 with keys derived from a pre-shared key.
+///
+/// Encrypted data is sent as records: a 2 byte (big endian) length followed by that many bytes
+/// of ciphertext. Each direction has its own key, and the nonce is a counter of the records sent
+/// in that direction, so a record that is tampered with, replayed or reordered fails to decrypt.
+pub struct Transport {
+    reader: TransportReader,
+    writer: TransportWriter,
+}
+
+pub struct TransportReader {
+    socket: OwnedReadHalf,
+    cipher: Option<DirectionalCipher>,
+    // Decrypted bytes that didn't fit in the caller's buffer
+    plaintext: Vec<u8>,
+    traffic: Traffic,
+}
+
+pub struct TransportWriter {
+    socket: OwnedWriteHalf,
+    cipher: Option<DirectionalCipher>,
+    traffic: Traffic,
+}
+
+/// The bytes that went over the socket since the transport handshake started, including the
+/// record lengths and authentication tags of encrypted data. Shared between the clones, so the
+/// reader and writer can count while running in their own tasks.
+#[derive(Debug, Clone, Default)]
+pub struct Traffic {
+    sent: Arc<AtomicU64>,
+    received: Arc<AtomicU64>,
+}
+
+impl Traffic {
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    fn add_sent(&self, len: usize) {
+        self.sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn add_received(&self, len: usize) {
+        self.received.fetch_add(len as u64, Ordering::Relaxed);
+    }
+}
+
+struct DirectionalCipher {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl DirectionalCipher {
+    fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+            counter: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> Nonce {
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        *Nonce::from_slice(&nonce)
+    }
+}
+
+impl Transport {
+    /// The host side of the transport handshake, done right after the TCP connection has been
+    /// accepted (and the client authenticated). Fails if the client doesn't have the same key.
+    pub async fn host(mut socket: TcpStream, key: Option<&str>) -> NetResult<Self> {
+        let traffic = Traffic::default();
+        let key = match key {
+            Some(key) => key,
+            None => {
+                socket.write_all(&[0]).await?;
+                traffic.add_sent(1);
+                return Ok(Self::new(socket, None, traffic));
+            }
+        };
+
+        let host_salt = random_salt()?;
+        socket.write_all(&[1]).await?;
+        socket.write_all(&host_salt).await?;
+        traffic.add_sent(1 + SALT_LEN);
+        let mut client_salt = [0; SALT_LEN];
+        socket.read_exact(&mut client_salt).await?;
+        traffic.add_received(SALT_LEN);
+
+        let (host_to_client, client_to_host) = derive_keys(key, &host_salt, &client_salt);
+        let keys = Some((&host_to_client, &client_to_host));
+        let mut transport = Self::new(socket, keys, traffic);
+        transport.confirm_key().await?;
+        Ok(transport)
+    }
+
+    /// The client side of the transport handshake. Fails if the host doesn't use the same key,
+    /// or if exactly one of the sides wants encryption.
+    pub async fn join(mut socket: TcpStream, key: Option<&str>) -> NetResult<Self> {
+        let traffic = Traffic::default();
+        let mut encrypted = [0];
+        socket.read_exact(&mut encrypted).await?;
+        traffic.add_received(1);
+        let key = match (encrypted[0], key) {
+            (0, None) => return Ok(Self::new(socket, None, traffic)),
+            (0, Some(_)) => return Err(handshake_error("The host doesn't use encryption")),
+            (_, None) => return Err(handshake_error("The host requires an encryption key")),
+            (_, Some(key)) => key,
+        };
+
+        let mut host_salt = [0; SALT_LEN];
+        socket.read_exact(&mut host_salt).await?;
+        traffic.add_received(SALT_LEN);
+        let client_salt = random_salt()?;
+        socket.write_all(&client_salt).await?;
+        traffic.add_sent(SALT_LEN);
+
+        let (host_to_client, client_to_host) = derive_keys(key, &host_salt, &client_salt);
+        let keys = Some((&client_to_host, &host_to_client));
+        let mut transport = Self::new(socket, keys, traffic);
+        transport.confirm_key().await?;
+        Ok(transport)
+    }
+
+    pub fn traffic(&self) -> Traffic {
+        self.writer.traffic.clone()
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.writer.cipher.is_some()
+    }
+
+    pub fn into_split(self) -> (TransportReader, TransportWriter) {
+        (self.reader, self.writer)
+    }
+
+    pub async fn read_exact(&mut self, buf: &mut [u8]) -> NetResult<()> {
+        self.reader.read_exact(buf).await
+    }
+
+    pub async fn write_all(&mut self, buf: &[u8]) -> NetResult<()> {
+        self.writer.write_all(buf).await
+    }
+
+    fn new(socket: TcpStream, keys: Option<(&[u8; 32], &[u8; 32])>, traffic: Traffic) -> Self {
+        let (read_half, write_half) = socket.into_split();
+        Self {
+            reader: TransportReader {
+                socket: read_half,
+                cipher: keys.map(|(_, receiving_key)| DirectionalCipher::new(receiving_key)),
+                plaintext: Vec::new(),
+                traffic: traffic.clone(),
+            },
+            writer: TransportWriter {
+                socket: write_half,
+                cipher: keys.map(|(sending_key, _)| DirectionalCipher::new(sending_key)),
+                traffic,
+            },
+        }
+    }
+
+    // Both sides send a known message as their first record. With mismatching keys, it fails to
+    // decrypt, and the handshake fails before any game data is exchanged.
+    async fn confirm_key(&mut self) -> NetResult<()> {
+        self.write_all(KEY_CONFIRMATION).await?;
+        let mut confirmation = [0; KEY_CONFIRMATION.len()];
+        self.read_exact(&mut confirmation)
+            .await
+            .map_err(|_| handshake_error("Encryption keys don't match"))?;
+        if confirmation != KEY_CONFIRMATION {
+            return Err(handshake_error("Encryption keys don't match"));
+        }
+        Ok(())
+    }
+}
+
+impl TransportReader {
+    /// Returns 0 once the remote has closed the connection
+    pub async fn read(&mut self, buf: &mut [u8]) -> NetResult<usize> {
+        let cipher = match &mut self.cipher {
+            Some(cipher) => cipher,
+            None => {
+                let n = self.socket.read(buf).await?;
+                self.traffic.add_received(n);
+                return Ok(n);
+            }
+        };
+
+        if self.plaintext.is_empty() {
+            let mut len = [0; 2];
+            match self.socket.read_exact(&mut len).await {
+                Ok(_) => {}
+                Err(error) if error.kind() == ErrorKind::UnexpectedEof => return Ok(0),
+                Err(error) => return Err(error),
+            }
+            let mut ciphertext = vec![0; u16::from_be_bytes(len) as usize];
+            self.socket.read_exact(&mut ciphertext).await?;
+            self.traffic.add_received(len.len() + ciphertext.len());
+
+            let nonce = cipher.next_nonce();
+            self.plaintext = cipher
+                .cipher
+                .decrypt(&nonce, &ciphertext[..])
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "Failed to decrypt record"))?;
+        }
+
+        let n = buf.len().min(self.plaintext.len());
+        buf[..n].copy_from_slice(&self.plaintext[..n]);
+        self.plaintext.drain(..n);
+        Ok(n)
+    }
+
+    pub async fn read_exact(&mut self, mut buf: &mut [u8]) -> NetResult<()> {
+        while !buf.is_empty() {
+            let n = self.read(buf).await?;
+            if n == 0 {
+                return Err(Error::from(ErrorKind::UnexpectedEof));
+            }
+            buf = &mut buf[n..];
+        }
+        Ok(())
+    }
+}
+
+impl TransportWriter {
+    pub async fn write_all(&mut self, buf: &[u8]) -> NetResult<()> {
+        let cipher = match &mut self.cipher {
+            Some(cipher) => cipher,
+            None => {
+                self.socket.write_all(buf).await?;
+                self.traffic.add_sent(buf.len());
+                return Ok(());
+            }
+        };
+
+        for chunk in buf.chunks(MAX_RECORD_PLAINTEXT) {
+            let nonce = cipher.next_nonce();
+            let ciphertext = cipher
+                .cipher
+                .encrypt(&nonce, chunk)
+                .map_err(|_| Error::other("Failed to encrypt record"))?;
+            let mut record = Vec::with_capacity(2 + ciphertext.len());
+            record.extend_from_slice(&(ciphertext.len() as u16).to_be_bytes());
+            record.extend_from_slice(&ciphertext);
+            self.socket.write_all(&record).await?;
+            self.traffic.add_sent(record.len());
+        }
+        Ok(())
+    }
+
+    pub async fn shutdown(&mut self) -> NetResult<()> {
+        self.socket.shutdown().await
+    }
+}
+
+fn derive_keys(
+    key: &str,
+    host_salt: &[u8; SALT_LEN],
+    client_salt: &[u8; SALT_LEN],
+) -> ([u8; 32], [u8; 32]) {
+    let mut salt = [0; SALT_LEN * 2];
+    salt[..SALT_LEN].copy_from_slice(host_salt);
+    salt[SALT_LEN..].copy_from_slice(client_salt);
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), key.as_bytes());
+
+    let mut host_to_client = [0; 32];
+    let mut client_to_host = [0; 32];
+    hkdf.expand(b"achtung host to client", &mut host_to_client)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    hkdf.expand(b"achtung client to host", &mut client_to_host)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    (host_to_client, client_to_host)
+}
+
+fn random_salt() -> NetResult<[u8; SALT_LEN]> {
+    let mut salt = [0; SALT_LEN];
+    getrandom::getrandom(&mut salt)?;
+    Ok(salt)
+}
+
+fn handshake_error(message: &str) -> Error {
+    Error::new(ErrorKind::PermissionDenied, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn connect(
+        host_key: Option<&str>,
+        client_key: Option<&str>,
+    ) -> (NetResult<Transport>, NetResult<Transport>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::join!(
+            async {
+                let (socket, _) = listener.accept().await?;
+                Transport::host(socket, host_key).await
+            },
+            async {
+                let socket = TcpStream::connect(address).await?;
+                Transport::join(socket, client_key).await
+            }
+        )
+    }
+
+    #[tokio::test]
+    async fn data_round_trips_with_matching_keys() {
+        let (host, client) = connect(Some("secret"), Some("secret")).await;
+        let (mut host, mut client) = (host.unwrap(), client.unwrap());
+        assert!(host.is_encrypted() && client.is_encrypted());
+
+        let sent_before = host.traffic().sent();
+        host.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // The record length and the authentication tag are counted too
+        assert_eq!(host.traffic().sent() - sent_before, 2 + 5 + 16);
+        assert_eq!(host.traffic().sent(), client.traffic().received());
+    }
+
+    #[tokio::test]
+    async fn data_round_trips_without_keys() {
+        let (host, client) = connect(None, None).await;
+        let (mut host, mut client) = (host.unwrap(), client.unwrap());
+        assert!(!host.is_encrypted() && !client.is_encrypted());
+
+        client.write_all(b"hi").await.unwrap();
+        let mut buf = [0; 2];
+        host.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hi");
+        assert_eq!(client.traffic().sent(), 2);
+        assert_eq!(host.traffic().received(), 2);
+    }
+
+    #[tokio::test]
+    async fn mismatched_keys_fail_to_confirm() {
+        let (host, client) = connect(Some("secret"), Some("guess")).await;
+        for result in [host, client] {
+            let error = result.err().unwrap();
+            assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+            assert_eq!(error.to_string(), "Encryption keys don't match");
+        }
+    }
+
+    #[tokio::test]
+    async fn a_key_on_one_side_only_fails() {
+        let (host, client) = connect(Some("secret"), None).await;
+        assert!(host.is_err());
+        let error = client.err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(error.to_string(), "The host requires an encryption key");
+
+        let (_, client) = connect(None, Some("secret")).await;
+        let error = client.err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(error.to_string(), "The host doesn't use encryption");
+    }
+
+    #[tokio::test]
+    async fn a_tampered_record_fails_to_decrypt() {
+        let (host, client) = connect(Some("secret"), Some("secret")).await;
+        let (mut host, mut client) = (host.unwrap(), client.unwrap());
+
+        let cipher = host.writer.cipher.as_mut().unwrap();
+        let nonce = cipher.next_nonce();
+        let mut ciphertext = cipher.cipher.encrypt(&nonce, &b"hello"[..]).unwrap();
+        ciphertext[0] ^= 1;
+        let mut record = (ciphertext.len() as u16).to_be_bytes().to_vec();
+        record.extend_from_slice(&ciphertext);
+        host.writer.socket.write_all(&record).await.unwrap();
+
+        let mut buf = [0; 5];
+        let error = client.read_exact(&mut buf).await.err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -1,10 +1,15 @@
-use crate::game::{Player, PlayerIndex, DOWN, LEFT, RIGHT, UP};
+use crate::game::{
+    FrameEvent, Game, Player, PlayerColor, PlayerIndex, PowerUp, DOWN, LEFT, RIGHT, UP,
+};
+use crate::net::NetStats;
+use crate::stats::Leaderboard;
 use crate::{game, Point};
 use backtrace::Backtrace;
 use crossterm::execute;
 use crossterm::terminal::{disable_raw_mode, ClearType};
 use crossterm::terminal::{enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
-use std::cmp::min;
+use std::cmp::{max, min};
+use std::collections::VecDeque;
 use std::io::Stdout;
 use std::io::Write;
 use std::{io, panic};
@@ -12,15 +17,27 @@ use tui::backend::CrosstermBackend;
 use tui::buffer::Buffer;
 use tui::layout::{Alignment, Constraint, Direction, Layout, Margin, Rect};
 use tui::style::{Color, Modifier, Style};
-use tui::widgets::{Block, BorderType, Borders, List, ListItem, Paragraph, Widget};
+use tui::text::{Span, Spans};
+use tui::widgets::{Block, BorderType, Borders, Clear, List, ListItem, Paragraph, Widget};
 use tui::Terminal;
 
+const CHAT_SCROLLBACK_LEN: usize = 4;
+
 pub struct TerminalUi {
     terminal: Terminal<CrosstermBackend<Stdout>>,
     game_size: (u16, u16),
     players: Vec<Player>,
+    round: u32,
     banner_text: String,
     banner_color: Color,
+    net_stats: Option<NetStats>,
+    show_net_stats: bool,
+    leaderboard: Option<Leaderboard>,
+    show_leaderboard: bool,
+    power_ups: Vec<(Point, PowerUp)>,
+    chat_enabled: bool,
+    chat_messages: VecDeque<(PlayerIndex, String)>,
+    chat_input: Option<String>,
 }
 
 impl TerminalUi {
@@ -35,11 +52,25 @@ impl TerminalUi {
             terminal,
             game_size,
             players,
+            round: 1,
             banner_text: Default::default(),
             banner_color: Color::White,
+            net_stats: None,
+            show_net_stats: false,
+            leaderboard: None,
+            show_leaderboard: false,
+            power_ups: vec![],
+            chat_enabled: false,
+            chat_messages: VecDeque::new(),
+            chat_input: None,
         }
     }
 
+    pub fn start_round(&mut self, round: u32, players: Vec<Player>) {
+        self.round = round;
+        self.players = players;
+    }
+
     pub fn set_player_line(&mut self, player_i: PlayerIndex, line: &[Point]) {
         self.players[player_i].line.clear();
         self.players[player_i].line.extend_from_slice(line);
@@ -53,24 +84,103 @@ impl TerminalUi {
         self.players[player_i].crashed = crashed;
     }
 
+    pub fn set_power_ups(&mut self, power_ups: &[(Point, PowerUp)]) {
+        self.power_ups = power_ups.to_vec();
+    }
+
     pub fn set_player_score(&mut self, player_i: PlayerIndex, score: u32) {
         self.players[player_i].score = score;
     }
 
+    /// Shows where everyone went in a frame that has just been run, and what happened to them
+    pub fn show_frame(&mut self, game: &Game, events: &[FrameEvent]) {
+        for (i, player) in game.players.iter().enumerate() {
+            self.set_player_line(i, &player.line);
+            self.set_player_direction(i, player.direction);
+        }
+        self.set_power_ups(&game.power_ups);
+
+        for event in events {
+            match *event {
+                FrameEvent::Boosted(_) => {}
+                FrameEvent::PlayerCrashed(i, cause) => {
+                    self.set_banner(Color::Yellow, &game.crash_message(i, cause));
+                    self.set_player_crashed(i, true);
+                }
+                FrameEvent::PlayerWon(i) => {
+                    let player = &game.players[i];
+                    self.set_banner(
+                        terminal_color(player.color),
+                        &format!("{} won!", player.name),
+                    );
+                }
+                FrameEvent::EveryoneCrashed => {
+                    self.set_banner(Color::Yellow, "Everyone crashed!");
+                    for i in 0..game.players.len() {
+                        self.set_player_crashed(i, true);
+                    }
+                }
+                FrameEvent::PowerUpTaken(i, power_up) => {
+                    let player = &game.players[i];
+                    let message = match power_up {
+                        PowerUp::SpeedUp => format!("{} sped up!", player.name),
+                        PowerUp::SlowDown => format!("{} slowed everyone else down!", player.name),
+                    };
+                    self.set_banner(terminal_color(player.color), &message);
+                }
+            }
+        }
+
+        for (i, player) in game.players.iter().enumerate() {
+            self.set_player_score(i, player.score);
+        }
+    }
+
     pub fn set_banner(&mut self, color: Color, text: &str) {
         self.banner_text.clear();
         self.banner_text.push_str(text);
         self.banner_color = color;
     }
 
+    pub fn set_net_stats(&mut self, stats: NetStats) {
+        self.net_stats = Some(stats);
+    }
+
+    pub fn toggle_net_stats(&mut self) {
+        self.show_net_stats = !self.show_net_stats;
+    }
+
+    pub fn set_leaderboard(&mut self, leaderboard: Leaderboard) {
+        self.leaderboard = Some(leaderboard);
+    }
+
+    pub fn toggle_leaderboard(&mut self) {
+        self.show_leaderboard = !self.show_leaderboard;
+    }
+
+    pub fn enable_chat(&mut self) {
+        self.chat_enabled = true;
+    }
+
+    pub fn add_chat_message(&mut self, player_i: PlayerIndex, text: String) {
+        if self.chat_messages.len() == CHAT_SCROLLBACK_LEN {
+            self.chat_messages.pop_front();
+        }
+        self.chat_messages.push_back((player_i, text));
+    }
+
+    pub fn set_chat_input(&mut self, input: Option<String>) {
+        self.chat_input = input;
+    }
+
     pub fn draw(&mut self) -> anyhow::Result<()> {
         self.terminal
             .draw(|frame| {
                 let game_container = Block::default()
                     .borders(Borders::ALL)
                     .title(format!(
-                        " Achtung ({}x{})",
-                        self.game_size.0, self.game_size.1
+                        " Achtung ({}x{}) round {} ",
+                        self.game_size.0, self.game_size.1, self.round
                     ))
                     .border_type(BorderType::Rounded);
 
@@ -100,7 +210,9 @@ impl TerminalUi {
                     min(desired_game_container_size.1, game_container_rect.height);
 
                 let mut sidebar_rect = horizontal_rects[1];
-                sidebar_rect.width = min(sidebar_rect.width, 20);
+                let longest_name = self.players.iter().map(|p| p.name.len()).max();
+                let desired_sidebar_width = max(20, longest_name.unwrap_or(0) as u16 + 10);
+                sidebar_rect.width = min(sidebar_rect.width, desired_sidebar_width);
                 sidebar_rect.height = min(sidebar_rect.height, (self.players.len() + 2) as u16);
 
                 let game_container_sub_rects = Layout::default()
@@ -129,7 +241,7 @@ impl TerminalUi {
                 let mut banner_rect = banner_container_rect;
                 banner_rect.height = min(banner_rect.height, 1);
 
-                let game = GameWidget(&self.players);
+                let game = GameWidget(&self.players, &self.power_ups);
                 let game_rect = game_container_sub_rects[1];
 
                 let sidebar_items: Vec<ListItem> = self
@@ -144,7 +256,7 @@ impl TerminalUi {
                                 .saturating_sub(2 + name_part.len() + score_part.len()),
                         );
                         ListItem::new(format!("{}{}{}", name_part, spaces, score_part))
-                            .style(Style::default().fg(p.color))
+                            .style(Style::default().fg(terminal_color(p.color)))
                     })
                     .collect();
                 let sidebar = List::new(sidebar_items).block(
@@ -159,6 +271,111 @@ impl TerminalUi {
 
                 frame.render_widget(game, game_rect);
                 frame.render_widget(sidebar, sidebar_rect);
+
+                if self.chat_enabled {
+                    let mut chat_items: Vec<ListItem> = self
+                        .chat_messages
+                        .iter()
+                        .map(|(player_i, text)| {
+                            let player = &self.players[*player_i];
+                            ListItem::new(Spans::from(vec![
+                                Span::styled(
+                                    player.name.clone(),
+                                    Style::default()
+                                        .fg(terminal_color(player.color))
+                                        .add_modifier(Modifier::BOLD),
+                                ),
+                                Span::raw(format!(": {}", text)),
+                            ]))
+                        })
+                        .collect();
+                    chat_items.push(match &self.chat_input {
+                        Some(input) => ListItem::new(format!("> {}_", input)),
+                        None => ListItem::new("[Enter] chat")
+                            .style(Style::default().fg(Color::DarkGray)),
+                    });
+
+                    let mut chat_rect = game_container_rect;
+                    chat_rect.y = game_container_rect.bottom();
+                    chat_rect.height = min(
+                        (CHAT_SCROLLBACK_LEN + 3) as u16,
+                        frame.size().bottom().saturating_sub(chat_rect.y),
+                    );
+                    let chat = List::new(chat_items).block(
+                        Block::default()
+                            .borders(Borders::ALL)
+                            .border_type(BorderType::Rounded)
+                            .title(" Chat "),
+                    );
+                    frame.render_widget(chat, chat_rect);
+                }
+
+                if let (true, Some(stats)) = (self.show_net_stats, &self.net_stats) {
+                    let net_stats_items = net_stats_lines(stats)
+                        .into_iter()
+                        .map(|(label, value)| {
+                            let spaces = " ".repeat(
+                                (sidebar_rect.width as usize)
+                                    .saturating_sub(2 + label.len() + value.len()),
+                            );
+                            ListItem::new(format!("{}{}{}", label, spaces, value))
+                        })
+                        .collect::<Vec<ListItem>>();
+                    let mut net_stats_rect = sidebar_rect;
+                    net_stats_rect.y = sidebar_rect.bottom();
+                    net_stats_rect.height = min(
+                        (net_stats_items.len() + 2) as u16,
+                        frame.size().bottom().saturating_sub(net_stats_rect.y),
+                    );
+                    let net_stats_panel = List::new(net_stats_items).block(
+                        Block::default()
+                            .borders(Borders::ALL)
+                            .border_type(BorderType::Rounded)
+                            .title(" Network "),
+                    );
+                    frame.render_widget(net_stats_panel, net_stats_rect);
+                }
+
+                // Drawn over the arena, as big as it needs to be
+                if self.show_leaderboard {
+                    let lines = match &self.leaderboard {
+                        Some(leaderboard) => leaderboard.lines(),
+                        None => vec![(None, "Stats aren't being recorded".to_string())],
+                    };
+                    let mut leaderboard_rect = game_container_rect;
+                    let longest_line = lines.iter().map(|(_, line)| line.len()).max();
+                    leaderboard_rect.width = min(
+                        max(
+                            longest_line.unwrap_or(0) as u16 + 2,
+                            game_container_rect.width,
+                        ),
+                        frame.size().width,
+                    );
+                    leaderboard_rect.height = min(
+                        max(lines.len() as u16 + 2, game_container_rect.height),
+                        frame.size().height,
+                    );
+                    let leaderboard_items: Vec<ListItem> = lines
+                        .into_iter()
+                        .map(|(name, line)| {
+                            let player =
+                                name.and_then(|name| self.players.iter().find(|p| p.name == name));
+                            match player {
+                                Some(player) => ListItem::new(line)
+                                    .style(Style::default().fg(terminal_color(player.color))),
+                                None => ListItem::new(line),
+                            }
+                        })
+                        .collect();
+                    let leaderboard_panel = List::new(leaderboard_items).block(
+                        Block::default()
+                            .borders(Borders::ALL)
+                            .border_type(BorderType::Rounded)
+                            .title(" Leaderboard [F2] "),
+                    );
+                    frame.render_widget(Clear, leaderboard_rect);
+                    frame.render_widget(leaderboard_panel, leaderboard_rect);
+                }
             })
             .unwrap();
 
@@ -166,10 +383,42 @@ impl TerminalUi {
     }
 }
 
-struct GameWidget<'a>(&'a [Player]);
+fn net_stats_lines(stats: &NetStats) -> Vec<(&'static str, String)> {
+    let millis =
+        |duration: std::time::Duration| format!("{:.1}ms", duration.as_secs_f64() * 1000.0);
+    vec![
+        (
+            "RTT",
+            stats.rtt.map(millis).unwrap_or_else(|| "?".to_string()),
+        ),
+        ("Jitter", millis(stats.jitter)),
+        ("Sent", format!("{}B", stats.bytes_sent)),
+        ("Received", format!("{}B", stats.bytes_received)),
+        (
+            "Waited",
+            format!("{} frames", stats.frames_waited_on_remote),
+        ),
+        ("Stall", millis(stats.longest_stall)),
+    ]
+}
+
+struct GameWidget<'a>(&'a [Player], &'a [(Point, PowerUp)]);
 
 impl Widget for GameWidget<'_> {
     fn render(self, area: Rect, buf: &mut Buffer) {
+        for (point, power_up) in self.1 {
+            let x = (area.x as i32 + point.0) as u16;
+            let y = (area.y as i32 + point.1) as u16;
+            if x <= area.right() && y <= area.bottom() {
+                let cell = buf.get_mut(x, y);
+                let (color, symbol) = match power_up {
+                    PowerUp::SpeedUp => (Color::LightGreen, "+"),
+                    PowerUp::SlowDown => (Color::LightRed, "-"),
+                };
+                cell.fg = color;
+                cell.set_symbol(symbol);
+            }
+        }
         for player in self.0 {
             for i in 0..player.line.len() {
                 let point = player.line[i];
@@ -177,7 +426,7 @@ impl Widget for GameWidget<'_> {
                 let y = (area.y as i32 + point.1) as u16;
                 if x <= area.right() && y <= area.bottom() {
                     let cell = buf.get_mut(x, y);
-                    cell.fg = player.color;
+                    cell.fg = terminal_color(player.color);
                     let symbol = if i < player.line.len() - 1 {
                         "#"
                     } else {
@@ -226,6 +475,25 @@ pub fn restore_terminal(terminal: &mut Terminal<CrosstermBackend<Stdout>>) {
     terminal.show_cursor().unwrap();
 }
 
+pub fn terminal_color(color: PlayerColor) -> Color {
+    match color {
+        PlayerColor::Red => Color::Red,
+        PlayerColor::Green => Color::Green,
+        PlayerColor::Yellow => Color::Yellow,
+        PlayerColor::Blue => Color::Blue,
+        PlayerColor::Magenta => Color::Magenta,
+        PlayerColor::Cyan => Color::Cyan,
+        PlayerColor::White => Color::White,
+        PlayerColor::Gray => Color::Gray,
+        PlayerColor::LightRed => Color::LightRed,
+        PlayerColor::LightGreen => Color::LightGreen,
+        PlayerColor::LightYellow => Color::LightYellow,
+        PlayerColor::LightBlue => Color::LightBlue,
+        PlayerColor::LightMagenta => Color::LightMagenta,
+        PlayerColor::LightCyan => Color::LightCyan,
+    }
+}
+
 fn setup_panic_handler() {
     panic::set_hook(Box::new(move |panic_info| {
         io::stdout().flush().unwrap();